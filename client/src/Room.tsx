@@ -1,7 +1,7 @@
 import { useState, useEffect, useRef } from "react";
 import Video from "./Video";
 import { useMedia } from "./Video";
-import { useCall, Peer } from "./ws";
+import { useCall, Peer, spectating } from "./ws";
 import { Pos, factor, useMap } from "./util";
 
 const size = {
@@ -10,32 +10,42 @@ const size = {
 };
 
 const Room = () => {
-  const media = useMedia();
-  const [selfs, peers] = useCall(media);
+  const media = useMedia(spectating);
+  const [selfs, peers, , , [stage, raiseHand]] = useCall(media);
 
   if (selfs == null) {
     return <div>Loading</div>;
   }
 
-  const [self, setPos] = selfs;
+  const [self, setPos, updatePresence] = selfs;
+  // Spectators are not on the map, so hear everyone they watch in full, as
+  // everyone hears whoever is on stage
+  const volume = (id: number, pos: Pos) =>
+    self.spectator || id == stage.peer ? 1 : factor(self.pos, pos);
+  const raised = stage.hands.some((id) => id == self.id);
 
-  const videos = peers.map(({ id, pos, stream }) => (
-    <Video
-      key={id}
-      pos={pos}
-      factor={factor(self.pos, pos)}
-      media={stream}
-    />
-  ));
+  const videos = peers.map(
+    ({ id, pos, stream, name, avatar, presence }) => (
+      <Video
+        key={id}
+        pos={pos}
+        factor={volume(id, pos)}
+        media={stream}
+        name={name}
+        avatar={avatar}
+        presence={presence}
+      />
+    ),
+  );
 
   const style = {
     width: `${size.width}px`,
     height: `${size.height}px`,
   };
 
-  const lines = peers.map(({ id, pos }) => (
+  const lines = (self.spectator ? [] : peers).map(({ id, pos }) => (
     <line
-      opacity={factor(self.pos, pos)}
+      opacity={volume(id, pos)}
       key={id}
       x1={self.pos.x - 80}
       y1={self.pos.y - 80}
@@ -44,7 +54,9 @@ const Room = () => {
     />
   ));
 
+  // Spectators are not on the map, so cannot move around it
   const handleClick = (e: React.MouseEvent<HTMLElement>) => {
+    if (self.spectator) return;
     setPos({
       x: e.clientX - e.currentTarget.clientLeft,
       y: e.clientY - e.currentTarget.clientTop,
@@ -55,11 +67,36 @@ const Room = () => {
     <div>
       <div style={style} className="room" onClick={handleClick}>
         {videos}
-        <Video pos={self.pos} media={media} />
+        {!self.spectator && (
+          <Video
+            pos={self.pos}
+            media={media}
+            name={self.name}
+            avatar={self.avatar}
+            presence={self.presence}
+          />
+        )}
       </div>
       <svg style={style} viewBox={`0 0 ${size.width} ${size.height}`}>
         {lines}
       </svg>
+      {!self.spectator && (
+        <button onClick={() => raiseHand(!raised)}>
+          {raised ? "Lower hand" : "Raise hand"}
+        </button>
+      )}
+      {!self.spectator && (
+        <label>
+          <input
+            type="checkbox"
+            checked={self.presence.allow_spectators}
+            onChange={(e) =>
+              updatePresence({ allow_spectators: e.target.checked })
+            }
+          />
+          Allow spectators
+        </label>
+      )}
     </div>
   );
 };
@@ -1,16 +1,25 @@
 import { useState, useEffect, useRef } from "react";
 import { Pos } from "./util";
+import { Presence } from "./ws";
 
 interface Props {
   pos: Pos;
   factor?: number;
   media: MediaStream | null;
+  name?: string;
+  avatar?: string;
+  presence?: Presence;
 }
 
-export const useMedia = () => {
+/** Asks for the camera and microphone, unless `receiveOnly` is set */
+export const useMedia = (receiveOnly = false) => {
   const [media, setMedia] = useState<null | MediaStream>(null);
 
   useEffect(() => {
+    if (receiveOnly) {
+      setMedia(new MediaStream());
+      return () => setMedia(null);
+    }
     const p = navigator.mediaDevices.getUserMedia({
       audio: true,
       video: { width: 320, height: 320 },
@@ -21,12 +30,21 @@ export const useMedia = () => {
       setMedia(null);
       p.then((media) => media.getTracks().forEach((t) => t.stop()));
     };
-  }, []);
+  }, [receiveOnly]);
 
   return media;
 };
 
-export default ({ pos, factor, media }: Props) => {
+const isUrl = (avatar: string) => /^https?:\/\//.test(avatar);
+
+export default ({
+  pos,
+  factor,
+  media,
+  name,
+  avatar,
+  presence,
+}: Props) => {
   const videoRef = useRef<HTMLVideoElement>(null);
   const volume = factor ?? 0;
 
@@ -35,7 +53,7 @@ export default ({ pos, factor, media }: Props) => {
       videoRef.current.srcObject = media;
       videoRef.current.play();
     }
-  }, [videoRef.current]);
+  }, [videoRef.current, media]);
 
   useEffect(() => {
     if (videoRef.current != null) videoRef.current.volume = volume;
@@ -53,15 +71,23 @@ export default ({ pos, factor, media }: Props) => {
   } else {
     videoStyle.opacity = factor;
   }
-
+  if (presence?.speaking) classes.push("speaking");
+  if (presence?.away) classes.push("away");
+  const showVideo = !presence?.camera_off && !presence?.away;
 
   return (
     <div style={containerStyle} className={classes.join(" ")}>
-      {(volume > 0 || factor == null) ? (
+      {media != null && showVideo && (volume > 0 || factor == null) ? (
         <video style={videoStyle} ref={videoRef} />
       ) : (
-        <div>Profile Pic</div>
+        <>
+          {avatar != null && isUrl(avatar) && (
+            <img className="profile" src={avatar} alt={name} />
+          )}
+          <div>{name ?? "Profile Pic"}</div>
+        </>
       )}
+      {presence?.muted && <div className="muted">Muted</div>}
     </div>
   );
 };
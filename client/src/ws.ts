@@ -14,11 +14,37 @@ type PeerMessage = {
     }
 );
 
+export type ChatScope =
+  | { type: "Peer"; peer: number }
+  | { type: "Nearby"; radius: number }
+  | { type: "Bubble" }
+  | { type: "Room" };
+
+export interface ChatMessage {
+  from: number;
+  scope: ChatScope;
+  text: string;
+  /** Milliseconds since the Unix epoch, as received by the server */
+  time: number;
+  /** Where a room-wide message is in the room's history */
+  id?: number;
+}
+
+interface HistoryEntry {
+  id: number;
+  from: number;
+  text: string;
+  time: number;
+}
+
 type ServerMessage =
   | {
       type: "Hello";
       state: { id: number } & PeerState;
       peers: ({ id: number } & PeerState)[];
+      resume: string;
+      protocol: { version: number; features: string[] };
+      quantum: number;
     }
   | {
       type: "AddPeer";
@@ -33,12 +59,72 @@ type ServerMessage =
       peer: number;
       pos: Pos;
     }
+  | ({
+      type: "Presence";
+      peer: number;
+    } & Presence)
+  | ({
+      type: "PeerUpdated";
+      peer: number;
+    } & Profile)
   | {
       type: "PeerMessage";
       message: PeerMessage;
+    }
+  | {
+      type: "Positions";
+      positions?: [number, number, number][];
+      deltas?: [number, number, number][];
+      zones?: [number, string | null][];
+    }
+  | ({ type: "Chat" } & ChatMessage)
+  | {
+      type: "History";
+      messages: HistoryEntry[];
+      more: boolean;
+    }
+  | {
+      type: "BubbleChanged";
+      bubble: number;
+      members: number[];
+    }
+  | ({ type: "Stage" } & Stage)
+  | {
+      type: "InRange";
+      peer: number;
+      polite: boolean;
+      /** One side is a spectator, and only the other sends media */
+      receive_only?: boolean;
+    }
+  | {
+      type: "OutOfRange";
+      peer: number;
+    }
+  | {
+      type: "MuteRequested";
+      by: number;
+    }
+  | {
+      type: "Error";
+      code: string;
+      message: string;
+      in_reply_to: string | null;
     };
 
+/** Commands only hosts may send */
+export type Moderation =
+  | { type: "Kick"; peer: number }
+  | { type: "RequestMute"; peer: number }
+  | { type: "Ban"; peer: number; ip?: boolean }
+  | { type: "Teleport"; peer: number; pos: Pos };
+
 type ClientMessage =
+  | Moderation
+  | { type: "Watch"; peer: number }
+  | { type: "Unwatch"; peer: number }
+  | { type: "SetStage"; peer: number | null }
+  | { type: "RaiseHand" }
+  | { type: "LowerHand" }
   | {
       type: "Peer";
       message: PeerMessage;
@@ -46,10 +132,66 @@ type ClientMessage =
   | {
       type: "Move";
       pos: Pos;
+    }
+  | ({ type: "Presence" } & Presence)
+  | ({ type: "SetProfile" } & Profile)
+  | {
+      type: "SetRadius";
+      radius: number;
+    }
+  | {
+      type: "Chat";
+      scope: ChatScope;
+      text: string;
+    }
+  | {
+      type: "History";
+      before?: number;
+      limit?: number;
     };
 
-interface PeerState {
+/** Whether to join as a spectator, who is not on the map and only receives media */
+export const spectating =
+  new URLSearchParams(window.location.search).get("spectate") == "1";
+
+/** Signalling protocol version this client speaks */
+const PROTOCOL = "webrtc.v2";
+
+interface Profile {
+  name?: string;
+  /** URL of a picture, or a hash to look one up by */
+  avatar?: string;
+  metadata?: { [key: string]: unknown };
+}
+
+export interface Presence {
+  muted: boolean;
+  camera_off: boolean;
+  away: boolean;
+  speaking: boolean;
+  /** Whether spectators may watch us */
+  allow_spectators: boolean;
+}
+
+export type Role = "host" | "member" | "spectator";
+
+export interface Stage {
+  /** Heard at full volume by everyone, wherever they are */
+  peer: number | null;
+  /** Peers waiting for the stage, in the order they raised their hand */
+  hands: number[];
+}
+
+interface PeerState extends Profile {
   pos: Pos;
+  role?: Role;
+  /** Only set on our own state, other spectators are not shown */
+  spectator?: boolean;
+  presence?: Presence;
+  /** Name of the map zone the peer is in */
+  zone?: string | null;
+  /** The conversation bubble the peer is in */
+  bubble?: number | null;
 }
 
 interface PeerConnection {
@@ -58,25 +200,66 @@ interface PeerConnection {
 }
 
 export interface Peer extends PeerState {
-  stream: MediaStream;
+  /** Absent while the peer is out of range */
+  stream: MediaStream | null;
 }
 
 const call = (
   media: MediaStream,
   selfCb: (id: number, state: Peer) => void,
   peerCb: (id: number, state: Peer | null) => void,
+  chatCb: (msgs: ChatMessage[], more?: boolean) => void,
+  muteCb: (by: number) => void,
+  stageCb: (stage: Stage) => void,
 ) => {
-  const { host } = window.location;
-  const ws = new WebSocket(`wss://${host}/${PUBLIC}/signalling/`);
+  const { host, search } = window.location;
+  const params = new URLSearchParams(search);
+  const room = params.get("room") ?? "default";
+  const token = params.get("token");
+  const peers = new Map<number, PeerState>();
+  // Only peers in range are connected to
   const connections = new Map<number, PeerConnection>();
-  const send = (msg: ClientMessage) => ws.send(JSON.stringify(msg));
+  const bubbles = new Map<number, number[]>();
 
   let self: number | null = null;
+  let selfState: PeerState = { pos: { x: 0, y: 0 } };
+  // Unit of the positions in `Positions` messages
+  let quantum = 1;
+  let resume: string | null = null;
+  let closed = false;
+  let ws: WebSocket;
+
+  const send = (msg: ClientMessage) => {
+    if (ws.readyState == WebSocket.OPEN) ws.send(JSON.stringify(msg));
+  };
+
+  const update = (id: number) => {
+    // Spectators watching us are not peers
+    const state = peers.get(id);
+    if (state == null) return;
+    const stream = connections.get(id)?.streams[0] ?? null;
+    peerCb(id, { ...state, stream });
+  };
 
-  const addPeer = (
-    { id, ...state }: { id: number } & PeerState,
-    polite: boolean,
-  ) => {
+  const setState = (id: number, change: Partial<PeerState>) => {
+    if (id == self) {
+      selfState = { ...selfState, ...change };
+      selfCb(id, { ...selfState, stream: media });
+    } else if (peers.has(id)) {
+      peers.set(id, { ...peers.get(id)!, ...change });
+      update(id);
+    }
+  };
+
+  // Spectators watch everyone who allows it
+  const watch = (id: number) => {
+    const allowed = peers.get(id)?.presence?.allow_spectators;
+    if (spectating && allowed && !connections.has(id)) {
+      send({ type: "Watch", peer: id });
+    }
+  };
+
+  const openConnection = (id: number, polite: boolean) => {
     const connection = new RTCPeerConnection();
 
     connection.addEventListener("icecandidate", ({ candidate }) => {
@@ -88,8 +271,8 @@ const call = (
       }
     });
     connection.addEventListener("track", ({ streams }) => {
-      connections.set(id, { ...state, connection, streams });
-      peerCb(id, { ...state, stream: streams[0] });
+      connections.set(id, { connection, streams });
+      update(id);
     });
     connection.addEventListener("negotiationneeded", async () => {
       await connection.setLocalDescription(await connection.createOffer());
@@ -107,13 +290,17 @@ const call = (
       media.getTracks().forEach((track) => connection.addTrack(track, media));
     }
 
-    connections.set(id, { ...state, connection, streams: [] });
+    connections.set(id, { connection, streams: [] });
   };
 
-  const removePeer = (id: number) => {
-    const { connection } = connections.get(id)!;
-    connection.close();
+  const closeConnection = (id: number) => {
+    connections.get(id)?.connection.close();
     connections.delete(id);
+  };
+
+  const removePeer = (id: number) => {
+    closeConnection(id);
+    peers.delete(id);
     peerCb(id, null);
   };
 
@@ -121,28 +308,105 @@ const call = (
     if (msg.type == "Hello") {
       const {
         state: { id, ...state },
-        peers,
       } = msg;
+      if (id != self) {
+        // Our previous session, if any, has expired
+        Array.from(peers.keys()).forEach(removePeer);
+        const profile = {
+          name: params.get("name") ?? undefined,
+          avatar: params.get("avatar") ?? undefined,
+        };
+        if (profile.name != null || profile.avatar != null) {
+          send({ type: "SetProfile", ...profile });
+        }
+      } else {
+        const current = new Set(msg.peers.map(({ id }) => id));
+        Array.from(peers.keys())
+          .filter((id) => !current.has(id))
+          .forEach(removePeer);
+      }
       self = id;
+      resume = msg.resume;
+      quantum = msg.quantum;
+      selfState = state;
       selfCb(id, { ...state, stream: media });
-      peers.forEach((p) => addPeer(p, true));
+      msg.peers.forEach(({ id, ...state }) => {
+        peers.set(id, state);
+        update(id);
+        watch(id);
+      });
     } else if (msg.type == "AddPeer") {
-      const { peer } = msg;
-      addPeer(peer, false);
+      const { id, ...state } = msg.peer;
+      peers.set(id, state);
+      update(id);
+      watch(id);
     } else if (msg.type == "RemovePeer") {
       const { peer } = msg;
       removePeer(peer);
+    } else if (msg.type == "InRange") {
+      const { peer, polite } = msg;
+      if (!connections.has(peer)) openConnection(peer, polite);
+    } else if (msg.type == "OutOfRange") {
+      const { peer } = msg;
+      closeConnection(peer);
+      update(peer);
+    } else if (msg.type == "Presence") {
+      const { type, peer, ...presence } = msg;
+      setState(peer, { presence });
+      watch(peer);
+    } else if (msg.type == "PeerUpdated") {
+      const { peer, name, avatar, metadata } = msg;
+      setState(peer, { name, avatar, metadata });
     } else if (msg.type == "MovePeer") {
       const { peer, pos } = msg;
-      if (peer == self) {
-        selfCb(peer, { pos, stream: media });
+      setState(peer, { pos });
+    } else if (msg.type == "Positions") {
+      (msg.positions ?? []).forEach(([id, x, y]) =>
+        setState(id, { pos: { x: x * quantum, y: y * quantum } }),
+      );
+      (msg.deltas ?? []).forEach(([id, dx, dy]) => {
+        const pos = id == self ? selfState.pos : peers.get(id)?.pos;
+        if (pos != null) {
+          setState(id, {
+            pos: { x: pos.x + dx * quantum, y: pos.y + dy * quantum },
+          });
+        }
+      });
+      (msg.zones ?? []).forEach(([id, zone]) => setState(id, { zone }));
+    } else if (msg.type == "BubbleChanged") {
+      const { bubble, members } = msg;
+      const stateOf = (id: number) => (id == self ? selfState : peers.get(id));
+      // Peers that left may already have been added to another bubble
+      (bubbles.get(bubble) ?? [])
+        .filter((id) => !members.includes(id) && stateOf(id)?.bubble == bubble)
+        .forEach((id) => setState(id, { bubble: null }));
+      members.forEach((id) => setState(id, { bubble }));
+      if (members.length > 0) {
+        bubbles.set(bubble, members);
       } else {
-        const { streams } = connections.get(peer)!;
-        peerCb(peer, { pos, stream: streams[0] });
+        bubbles.delete(bubble);
       }
+    } else if (msg.type == "Chat") {
+      const { type, ...chat } = msg;
+      chatCb([chat]);
+    } else if (msg.type == "History") {
+      const scope: ChatScope = { type: "Room" };
+      chatCb(
+        msg.messages.map((entry) => ({ ...entry, scope })),
+        msg.more,
+      );
+    } else if (msg.type == "MuteRequested") {
+      muteCb(msg.by);
+    } else if (msg.type == "Stage") {
+      const { type, ...stage } = msg;
+      stageCb(stage);
+    } else if (msg.type == "Error") {
+      console.warn(`Signalling error ${msg.code}: ${msg.message}`);
     } else if (msg.type == "PeerMessage") {
       const { peer } = msg.message;
-      const { connection } = connections.get(peer)!;
+      // The peer may have just gone out of range
+      const { connection } = connections.get(peer) ?? {};
+      if (connection == null) return;
       if (msg.message.type == "ICECandidate") {
         await connection.addIceCandidate(msg.message.data);
       } else if (msg.message.type == "SDP") {
@@ -168,25 +432,87 @@ const call = (
     }
   };
 
-  ws.addEventListener("message", ({ data }) =>
-    handler(JSON.parse(data) as ServerMessage),
-  );
+  const connect = () => {
+    const query = new URLSearchParams();
+    if (token != null) query.set("token", token);
+    if (spectating) query.set("spectate", "1");
+    if (self != null && resume != null) {
+      query.set("session", self.toString());
+      query.set("resume", resume);
+    }
 
-  return ws;
+    ws = new WebSocket(
+      `wss://${host}/${PUBLIC}/signalling/room/${encodeURIComponent(room)}?${query}`,
+      [PROTOCOL],
+    );
+    ws.addEventListener("message", ({ data }) =>
+      handler(JSON.parse(data) as ServerMessage),
+    );
+    // Resume the session if the connection drops, rather than leaving the room
+    ws.addEventListener("close", ({ code, reason }) => {
+      // Policy violation, we were kicked or banned
+      if (code == 1008) {
+        console.warn(`Removed from the room: ${reason}`);
+        closed = true;
+      }
+      if (!closed) setTimeout(connect, 1000);
+    });
+  };
+  connect();
+
+  return {
+    send,
+    close: () => {
+      closed = true;
+      ws.close();
+    },
+  };
 };
 
 export const useCall = (
   media: MediaStream | null,
-): [[Peer, (pos: Pos) => void] | null, (Peer & { id: number })[]] => {
+): [
+  | [
+      Peer & { id: number },
+      (pos: Pos) => void,
+      (change: Partial<Presence>) => void,
+    ]
+  | null,
+  (Peer & { id: number })[],
+  [
+    ChatMessage[],
+    (scope: ChatScope, text: string) => void,
+    /** Asks for older room-wide messages, null if there are none */
+    (() => void) | null,
+  ],
+  (msg: Moderation) => void,
+  [
+    Stage,
+    (raised: boolean) => void,
+    /** Hosts may give the stage to anyone, and whoever has it may pass it on */
+    (peer: number | null) => void,
+  ],
+] => {
   const [peers, updatePeers] = useMap<number, Peer>();
-  const [self, setSelf] = useState<Peer | null>(null);
+  const [self, setSelf] = useState<(Peer & { id: number }) | null>(null);
   const [pos, setPos] = useState<Pos>({ x: 0, y: 0 });
+  const [presence, setPresence] = useState<Presence>({
+    muted: false,
+    camera_off: false,
+    away: false,
+    speaking: false,
+    allow_spectators: false,
+  });
+  const [chat, setChat] = useState<ChatMessage[]>([]);
+  const [more, setMore] = useState(false);
+  const [stage, setStage] = useState<Stage>({ peer: null, hands: [] });
 
   const sendRef = useRef<(msg: ClientMessage) => void>(() => {});
 
-  const selfCb = useCallback((id: number, state: Peer) => setSelf(state), [
-    setSelf,
-  ]);
+  const selfCb = useCallback(
+    (id: number, state: Peer) => setSelf({ id, ...state }),
+    [setSelf],
+  );
   const peerCb = useCallback(
     (id: number, state: Peer | null) => {
       if (state == null) {
@@ -197,20 +523,85 @@ export const useCall = (
     },
     [updatePeers.remove, updatePeers.insert],
   );
+  const chatCb = useCallback(
+    (msgs: ChatMessage[], more?: boolean) => {
+      // History is sent again on reconnecting, and may overlap what we have
+      setChat((chat) => {
+        const known = new Set(
+          chat.map(({ id }) => id).filter((id) => id != null),
+        );
+        const added = msgs.filter(({ id }) => id == null || !known.has(id));
+        return [...chat, ...added].sort((a, b) => a.time - b.time);
+      });
+      if (more != null) setMore(more);
+    },
+    [setChat, setMore],
+  );
+  const say = useCallback(
+    (scope: ChatScope, text: string) =>
+      sendRef.current({ type: "Chat", scope, text }),
+    [],
+  );
+
+  const oldest = chat.find(({ id }) => id != null)?.id;
+  const older = useCallback(() => {
+    setMore(false);
+    sendRef.current({ type: "History", before: oldest });
+  }, [oldest, setMore]);
+
+  const updatePresence = useCallback(
+    (change: Partial<Presence>) =>
+      setPresence((presence) => ({ ...presence, ...change })),
+    [setPresence],
+  );
+  const muteCb = useCallback(
+    (by: number) => {
+      media?.getAudioTracks().forEach((track) => (track.enabled = false));
+      updatePresence({ muted: true });
+    },
+    [media, updatePresence],
+  );
+  const moderate = useCallback(
+    (msg: Moderation) => sendRef.current(msg),
+    [],
+  );
+  const raiseHand = useCallback(
+    (raised: boolean) =>
+      sendRef.current({ type: raised ? "RaiseHand" : "LowerHand" }),
+    [],
+  );
+  const giveStage = useCallback(
+    (peer: number | null) => sendRef.current({ type: "SetStage", peer }),
+    [],
+  );
 
   useEffect(() => {
     if (media == null) return;
-    const ws = call(media, selfCb, peerCb);
-    sendRef.current = (msg) => ws.send(JSON.stringify(msg));
-    return () => ws.close();
-  }, [media, selfCb, peerCb]);
+    const { send, close } = call(
+      media,
+      selfCb,
+      peerCb,
+      chatCb,
+      muteCb,
+      setStage,
+    );
+    sendRef.current = send;
+    return close;
+  }, [media, selfCb, peerCb, chatCb, muteCb, setStage]);
 
   useEffect(() => {
     sendRef.current({ type: "Move", pos });
   }, [pos]);
 
+  useEffect(() => {
+    sendRef.current({ type: "Presence", ...presence });
+  }, [presence]);
+
   return [
-    self != null ? [self, setPos] : null,
+    self != null ? [{ ...self, presence }, setPos, updatePresence] : null,
     Array.from(peers.entries(), ([id, peer]) => ({ id, ...peer })),
+    [chat, say, more ? older : null],
+    moderate,
+    [stage, raiseHand, giveStage],
   ];
 };
@@ -37,6 +37,7 @@ in {
           proxy_http_version 1.1 ;
           proxy_set_header Upgrade $http_upgrade ;
           proxy_set_header Connection "upgrade" ;
+          proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for ;
           proxy_read_timeout 86400 ;
         '';
 
@@ -51,7 +52,8 @@ in {
       wantedBy = ["multi-user.target"];
       serviceConfig = {
         Type = "simple";
-        ExecStart = "${backend}/bin/signalling ${cfg.backend.address}";
+        # Clients are only told apart by address if the backend knows nginx is passing them on
+        ExecStart = "${backend}/bin/signalling ${cfg.backend.address} --trusted-proxy 127.0.0.1 --trusted-proxy ::1";
       };
     };
   };
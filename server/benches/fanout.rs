@@ -0,0 +1,164 @@
+//! Times how long the signalling server takes to fan out moves in a crowded room.
+//!
+//! Peers are spread out on a square grid so each only has a few neighbours, then all of them
+//! move at once. Run with `cargo bench --bench fanout -- [peers] [moves]`.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use tokio::runtime;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use webrtc::signalling::message::{ClientMessage, PeerId, Pos, ServerMessage};
+use webrtc::signalling::{self, protocol, Config, Map};
+
+const ADDRESS: &str = "localhost:4099";
+
+fn encode(msg: &ClientMessage) -> Message {
+    Message::Text(serde_json::to_string(msg).unwrap())
+}
+
+/// Waits until every peer has seen its own move to the position it was last sent to.
+async fn settle(
+    echoes: &mut mpsc::UnboundedReceiver<(PeerId, Pos)>,
+    expected: &HashMap<PeerId, (f32, f32)>,
+) {
+    let mut pending = expected.len();
+    while pending > 0 {
+        let (id, pos) = echoes.next().await.expect("Peer disconnected");
+        if expected[&id] == (pos.x, pos.y) {
+            pending -= 1;
+        }
+    }
+}
+
+async fn run(peers: usize, moves: usize, spacing: f32) -> Result<(), tungstenite::Error> {
+    let received = Arc::new(AtomicU64::new(0));
+    let (echo_tx, mut echoes) = mpsc::unbounded();
+    let mut sinks = Vec::with_capacity(peers);
+
+    for _ in 0..peers {
+        let request = Request::builder()
+            .uri(format!("ws://{}/room/bench", ADDRESS))
+            .header("Sec-WebSocket-Protocol", protocol::CURRENT)
+            .body(())
+            .unwrap();
+        let (ws, _) = tokio_tungstenite::connect_async(request).await?;
+        let (sink, mut source) = ws.split();
+
+        let (id, quantum) = match source.next().await {
+            Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                Ok(ServerMessage::Hello { state, quantum, .. }) => (state.id, quantum),
+                _ => panic!("Expected Hello, got {}", text),
+            },
+            msg => panic!("Expected Hello, got {:?}", msg),
+        };
+
+        let (received, echo_tx) = (received.clone(), echo_tx.clone());
+        tokio::spawn(async move {
+            while let Some(Ok(msg)) = source.next().await {
+                received.fetch_add(1, Ordering::Relaxed);
+                let text = match msg {
+                    Message::Text(text) => text,
+                    _ => continue,
+                };
+                if let Ok(ServerMessage::Positions { positions, .. }) = serde_json::from_str(&text)
+                {
+                    for (_, x, y) in positions.into_iter().filter(|&(peer, _, _)| peer == id) {
+                        let (x, y) = (x as f32 * quantum, y as f32 * quantum);
+                        let _ = echo_tx.unbounded_send((id, Pos { x, y }));
+                    }
+                }
+            }
+        });
+        sinks.push((id, sink));
+    }
+
+    let side = grid_side(peers);
+    let spots = (0..peers)
+        .map(|i| ((i % side) as f32 * spacing, (i / side) as f32 * spacing))
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let mut expected = HashMap::new();
+    for ((id, sink), &(x, y)) in sinks.iter_mut().zip(&spots) {
+        let pos = Pos { x, y };
+        sink.send(encode(&ClientMessage::Move { pos })).await?;
+        expected.insert(*id, (x, y));
+    }
+    settle(&mut echoes, &expected).await;
+    println!("Spread {} peers out in {:?}", peers, start.elapsed());
+
+    received.store(0, Ordering::Relaxed);
+    let start = Instant::now();
+    for step in 1..=moves {
+        for ((id, sink), &(x, y)) in sinks.iter_mut().zip(&spots) {
+            let pos = Pos {
+                x: x + step as f32,
+                y,
+            };
+            sink.send(encode(&ClientMessage::Move { pos })).await?;
+            expected.insert(*id, (pos.x, pos.y));
+        }
+    }
+    settle(&mut echoes, &expected).await;
+    let elapsed = start.elapsed();
+
+    let total = (peers * moves) as f64;
+    println!(
+        "{} moves in {:?}: {:.0} moves/s, {:.1} messages received per move",
+        total,
+        elapsed,
+        total / elapsed.as_secs_f64(),
+        received.load(Ordering::Relaxed) as f64 / total,
+    );
+    Ok(())
+}
+
+/// How many peers go on each side of the grid.
+fn grid_side(peers: usize) -> usize {
+    (peers as f32).sqrt().ceil() as usize
+}
+
+fn main() -> Result<(), tungstenite::Error> {
+    // `cargo bench` passes its own flags through
+    let mut args = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .map(|arg| arg.parse::<usize>().expect("Invalid count"));
+    let peers = args.next().unwrap_or(2000);
+    let moves = args.next().unwrap_or(10);
+
+    // A radius apart, so each peer is in range of a handful of others
+    let spacing = Config::default().interest_radius;
+    // Large enough to hold the whole grid, and for each peer to move one step per move beyond
+    // it, as peers would otherwise be stopped at the walls and never see their moves
+    let extent = grid_side(peers) as f32 * spacing + moves as f32;
+    let map = Map {
+        bounds: Pos {
+            x: extent,
+            y: extent,
+        },
+        ..Map::default()
+    };
+
+    let config = Config {
+        address: ADDRESS.to_owned(),
+        // Every peer moves at once, more often than a real client would
+        outbox_limit: moves * peers,
+        map,
+        ..Config::default()
+    };
+    std::thread::spawn(move || signalling::main(config));
+    std::thread::sleep(Duration::from_millis(100));
+
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(run(peers, moves, spacing))
+}
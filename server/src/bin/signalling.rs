@@ -1,13 +1,345 @@
 use webrtc::signalling;
 
+use std::time::Duration;
+
 use clap::{Arg, App};
 
 fn main() -> Result<(), signalling::Error> {
     let matches = App::new("Signalling server")
 	.arg(Arg::with_name("address"))
+	.arg(Arg::with_name("signalling-path")
+	     .long("signalling-path")
+	     .takes_value(true)
+	     .help("Path WebSocket connections are accepted on, with the rooms below it"))
+	.arg(Arg::with_name("static-dir")
+	     .long("static-dir")
+	     .takes_value(true)
+	     .help("Directory of files to serve, such as the built client"))
+	.arg(Arg::with_name("static-path")
+	     .long("static-path")
+	     .takes_value(true)
+	     .help("Path to serve the static directory under"))
+	.arg(Arg::with_name("allowed-origin")
+	     .long("allowed-origin")
+	     .takes_value(true)
+	     .multiple(true)
+	     .number_of_values(1)
+	     .help("Origin browsers may connect from, may be given more than once. Any origin is allowed if not given."))
+	.arg(Arg::with_name("trusted-proxy")
+	     .long("trusted-proxy")
+	     .takes_value(true)
+	     .multiple(true)
+	     .number_of_values(1)
+	     .help("Address of a reverse proxy whose X-Forwarded-For header is trusted, may be given more than once"))
+	.arg(Arg::with_name("max-connections-per-ip")
+	     .long("max-connections-per-ip")
+	     .takes_value(true)
+	     .help("Connections each address may have open at once"))
+	.arg(Arg::with_name("accept-burst")
+	     .long("accept-burst")
+	     .takes_value(true)
+	     .help("Connections each address may open at once, before being limited to --accept-interval"))
+	.arg(Arg::with_name("accept-interval")
+	     .long("accept-interval")
+	     .takes_value(true)
+	     .help("Seconds between connections each address may open once it has used up its burst"))
+	.arg(Arg::with_name("outbox-limit")
+	     .long("outbox-limit")
+	     .takes_value(true)
+	     .help("Messages queued for a peer before it is disconnected as too slow"))
+	.arg(Arg::with_name("token-secret-file")
+	     .long("token-secret-file")
+	     .takes_value(true)
+	     .help("File holding the key join tokens are signed with, tokens are required if set"))
+	.arg(Arg::with_name("resume-grace")
+	     .long("resume-grace")
+	     .takes_value(true)
+	     .help("Seconds a disconnected peer keeps its place in the room, to resume its session"))
+	.arg(Arg::with_name("ping-interval")
+	     .long("ping-interval")
+	     .takes_value(true)
+	     .help("Seconds between pings sent to each peer"))
+	.arg(Arg::with_name("idle-timeout")
+	     .long("idle-timeout")
+	     .takes_value(true)
+	     .help("Seconds without hearing from a peer before its connection is considered lost"))
+	.arg(Arg::with_name("max-protocol-errors")
+	     .long("max-protocol-errors")
+	     .takes_value(true)
+	     .help("Malformed messages a peer may send at once before it is disconnected"))
+	.arg(Arg::with_name("protocol-error-interval")
+	     .long("protocol-error-interval")
+	     .takes_value(true)
+	     .help("Seconds after which one of a peer's malformed messages is forgiven"))
+	.arg(Arg::with_name("max-message-size")
+	     .long("max-message-size")
+	     .takes_value(true)
+	     .help("Largest message peers may send, in bytes, above which they are disconnected"))
+	.arg(Arg::with_name("max-relay-size")
+	     .long("max-relay-size")
+	     .takes_value(true)
+	     .help("Largest SDP or ICE candidate message peers may send, in bytes"))
+	.arg(Arg::with_name("max-movement-size")
+	     .long("max-movement-size")
+	     .takes_value(true)
+	     .help("Largest Move or SetRadius message peers may send, in bytes"))
+	.arg(Arg::with_name("max-control-size")
+	     .long("max-control-size")
+	     .takes_value(true)
+	     .help("Largest message of any other type peers may send, in bytes"))
+	.arg(Arg::with_name("relay-burst")
+	     .long("relay-burst")
+	     .takes_value(true)
+	     .help("SDP and ICE candidate messages peers may send at once, before being limited to --relay-interval"))
+	.arg(Arg::with_name("relay-interval")
+	     .long("relay-interval")
+	     .takes_value(true)
+	     .help("Seconds between SDP or ICE candidate messages once peers have used up their burst"))
+	.arg(Arg::with_name("movement-burst")
+	     .long("movement-burst")
+	     .takes_value(true)
+	     .help("Moves peers may send at once, before being limited to --movement-interval"))
+	.arg(Arg::with_name("movement-interval")
+	     .long("movement-interval")
+	     .takes_value(true)
+	     .help("Seconds between moves once peers have used up their burst"))
+	.arg(Arg::with_name("control-burst")
+	     .long("control-burst")
+	     .takes_value(true)
+	     .help("Other messages peers may send at once, before being limited to --control-interval"))
+	.arg(Arg::with_name("control-interval")
+	     .long("control-interval")
+	     .takes_value(true)
+	     .help("Seconds between other messages once peers have used up their burst"))
+	.arg(Arg::with_name("interest-radius")
+	     .long("interest-radius")
+	     .takes_value(true)
+	     .help("Distance within which peers connect to each other"))
+	.arg(Arg::with_name("max-interest-radius")
+	     .long("max-interest-radius")
+	     .takes_value(true)
+	     .help("Largest radius peers may ask for"))
+	.arg(Arg::with_name("interest-hysteresis")
+	     .long("interest-hysteresis")
+	     .takes_value(true)
+	     .help("Extra distance peers must move apart before disconnecting"))
+	.arg(Arg::with_name("tick-rate")
+	     .long("tick-rate")
+	     .takes_value(true)
+	     .help("Position updates sent per second"))
+	.arg(Arg::with_name("position-quantum")
+	     .long("position-quantum")
+	     .takes_value(true)
+	     .help("Precision positions are sent with"))
+	.arg(Arg::with_name("position-deltas")
+	     .long("position-deltas")
+	     .help("Send position updates as changes since the last one"))
+	.arg(Arg::with_name("map")
+	     .long("map")
+	     .takes_value(true)
+	     .help("JSON file with the layout of rooms"))
+	.arg(Arg::with_name("room-size")
+	     .long("room-size")
+	     .takes_value(true)
+	     .help("Size of rooms, as <width>x<height>, overriding the map"))
+	.arg(Arg::with_name("max-speed")
+	     .long("max-speed")
+	     .takes_value(true)
+	     .help("Distance peers may move per second"))
+	.arg(Arg::with_name("max-chat-length")
+	     .long("max-chat-length")
+	     .takes_value(true)
+	     .help("Maximum characters in a chat message"))
+	.arg(Arg::with_name("chat-burst")
+	     .long("chat-burst")
+	     .takes_value(true)
+	     .help("Chat messages a peer may send at once before being rate limited"))
+	.arg(Arg::with_name("chat-interval")
+	     .long("chat-interval")
+	     .takes_value(true)
+	     .help("Seconds after which a rate limited peer may send another chat message"))
+	.arg(Arg::with_name("speaking-burst")
+	     .long("speaking-burst")
+	     .takes_value(true)
+	     .help("Speaking flag changes a peer may send at once before being rate limited"))
+	.arg(Arg::with_name("speaking-interval")
+	     .long("speaking-interval")
+	     .takes_value(true)
+	     .help("Seconds between speaking flag changes passed on once a peer is rate limited"))
+	.arg(Arg::with_name("max-name-length")
+	     .long("max-name-length")
+	     .takes_value(true)
+	     .help("Maximum characters in a peer's display name"))
+	.arg(Arg::with_name("max-metadata-size")
+	     .long("max-metadata-size")
+	     .takes_value(true)
+	     .help("Maximum bytes of JSON metadata in a peer's profile"))
+	.arg(Arg::with_name("history-dir")
+	     .long("history-dir")
+	     .takes_value(true)
+	     .help("Directory to save room-wide chat in, so it survives restarts"))
+	.arg(Arg::with_name("history-length")
+	     .long("history-length")
+	     .takes_value(true)
+	     .help("Room-wide chat messages kept for each room"))
+	.arg(Arg::with_name("history-page")
+	     .long("history-page")
+	     .takes_value(true)
+	     .help("Chat messages sent to peers when they join, and the most they may ask for at once"))
+	.arg(Arg::with_name("tls-cert")
+	     .long("tls-cert")
+	     .takes_value(true)
+	     .requires("tls-key")
+	     .help("PEM certificate chain to serve TLS with, reloaded when it changes"))
+	.arg(Arg::with_name("tls-key")
+	     .long("tls-key")
+	     .takes_value(true)
+	     .requires("tls-cert")
+	     .help("PEM private key for the TLS certificate"))
 	.get_matches();
 
-    let address = matches.value_of("address").unwrap_or("localhost:4000");
+    let mut config = signalling::Config::default();
+    if let Some(address) = matches.value_of("address") {
+	config.address = address.to_owned();
+    }
+    if let Some(path) = matches.value_of("signalling-path") {
+	config.signalling_path = path.to_owned();
+    }
+    if let Some(dir) = matches.value_of("static-dir") {
+	config.static_dir = Some(dir.into());
+    }
+    if let Some(path) = matches.value_of("static-path") {
+	config.static_path = path.to_owned();
+    }
+    if let Some(origins) = matches.values_of("allowed-origin") {
+	config.allowed_origins = Some(origins.map(str::to_owned).collect());
+    }
+    if let Some(proxies) = matches.values_of("trusted-proxy") {
+	config.trusted_proxies = proxies.map(|proxy| proxy.parse().expect("Invalid proxy address")).collect();
+    }
+    if let Some(max) = matches.value_of("max-connections-per-ip") {
+	config.max_connections_per_ip = Some(max.parse().expect("Invalid connection limit"));
+    }
+    if let Some(burst) = matches.value_of("accept-burst") {
+	config.accept_burst = burst.parse().expect("Invalid accept burst");
+    }
+    if let Some(secs) = matches.value_of("accept-interval") {
+	config.accept_interval = Some(Duration::from_secs_f32(secs.parse().expect("Invalid accept interval")));
+    }
+    if let Some(limit) = matches.value_of("outbox-limit") {
+	config.outbox_limit = limit.parse().expect("Invalid outbox limit");
+    }
+    if let Some(secs) = matches.value_of("resume-grace") {
+	config.resume_grace = Duration::from_secs(secs.parse().expect("Invalid resume grace period"));
+    }
+    if let Some(secs) = matches.value_of("ping-interval") {
+	config.ping_interval = Duration::from_secs(secs.parse().expect("Invalid ping interval"));
+    }
+    if let Some(secs) = matches.value_of("idle-timeout") {
+	config.idle_timeout = Duration::from_secs(secs.parse().expect("Invalid idle timeout"));
+    }
+    if let Some(max) = matches.value_of("max-protocol-errors") {
+	config.max_protocol_errors = max.parse().expect("Invalid protocol error limit");
+    }
+    if let Some(secs) = matches.value_of("protocol-error-interval") {
+	config.protocol_error_interval = Duration::from_secs_f32(secs.parse().expect("Invalid protocol error interval"));
+    }
+    if let Some(size) = matches.value_of("max-message-size") {
+	config.max_message_size = size.parse().expect("Invalid message size");
+    }
+    if let Some(size) = matches.value_of("max-relay-size") {
+	config.max_relay_size = size.parse().expect("Invalid relay message size");
+    }
+    if let Some(size) = matches.value_of("max-movement-size") {
+	config.max_movement_size = size.parse().expect("Invalid movement message size");
+    }
+    if let Some(size) = matches.value_of("max-control-size") {
+	config.max_control_size = size.parse().expect("Invalid control message size");
+    }
+    if let Some(burst) = matches.value_of("relay-burst") {
+	config.relay_burst = burst.parse().expect("Invalid relay burst");
+    }
+    if let Some(secs) = matches.value_of("relay-interval") {
+	config.relay_interval = Duration::from_secs_f32(secs.parse().expect("Invalid relay interval"));
+    }
+    if let Some(burst) = matches.value_of("movement-burst") {
+	config.movement_burst = burst.parse().expect("Invalid movement burst");
+    }
+    if let Some(secs) = matches.value_of("movement-interval") {
+	config.movement_interval = Duration::from_secs_f32(secs.parse().expect("Invalid movement interval"));
+    }
+    if let Some(burst) = matches.value_of("control-burst") {
+	config.control_burst = burst.parse().expect("Invalid control burst");
+    }
+    if let Some(secs) = matches.value_of("control-interval") {
+	config.control_interval = Duration::from_secs_f32(secs.parse().expect("Invalid control interval"));
+    }
+    if let Some(radius) = matches.value_of("interest-radius") {
+	config.interest_radius = radius.parse().expect("Invalid interest radius");
+    }
+    if let Some(radius) = matches.value_of("max-interest-radius") {
+	config.max_interest_radius = radius.parse().expect("Invalid interest radius");
+    }
+    if let Some(margin) = matches.value_of("interest-hysteresis") {
+	config.interest_hysteresis = margin.parse().expect("Invalid interest hysteresis");
+    }
+    if let Some(rate) = matches.value_of("tick-rate") {
+	let rate: u32 = rate.parse().expect("Invalid tick rate");
+	config.tick = Duration::from_secs(1) / rate.max(1);
+    }
+    if let Some(quantum) = matches.value_of("position-quantum") {
+	config.position_quantum = quantum.parse().expect("Invalid position quantum");
+    }
+    config.position_deltas = matches.is_present("position-deltas");
+    if let Some(path) = matches.value_of("map") {
+	config.map = signalling::Map::load(path)?;
+    }
+    if let Some(size) = matches.value_of("room-size") {
+	let mut size = size.splitn(2, 'x').map(|n| n.parse().expect("Invalid room size"));
+	let (x, y) = (size.next().unwrap(), size.next().expect("Invalid room size"));
+	config.map.bounds = signalling::message::Pos { x, y };
+    }
+    if let Some(speed) = matches.value_of("max-speed") {
+	config.max_speed = Some(speed.parse().expect("Invalid maximum speed"));
+    }
+    if let Some(length) = matches.value_of("max-chat-length") {
+	config.max_chat_length = length.parse().expect("Invalid maximum chat length");
+    }
+    if let Some(burst) = matches.value_of("chat-burst") {
+	config.chat_burst = burst.parse().expect("Invalid chat burst");
+    }
+    if let Some(secs) = matches.value_of("chat-interval") {
+	config.chat_interval = Duration::from_secs_f32(secs.parse().expect("Invalid chat interval"));
+    }
+    if let Some(burst) = matches.value_of("speaking-burst") {
+	config.speaking_burst = burst.parse().expect("Invalid speaking burst");
+    }
+    if let Some(secs) = matches.value_of("speaking-interval") {
+	config.speaking_interval = Duration::from_secs_f32(secs.parse().expect("Invalid speaking interval"));
+    }
+    if let Some(length) = matches.value_of("max-name-length") {
+	config.max_name_length = length.parse().expect("Invalid maximum name length");
+    }
+    if let Some(size) = matches.value_of("max-metadata-size") {
+	config.max_metadata_size = size.parse().expect("Invalid maximum metadata size");
+    }
+    if let Some(dir) = matches.value_of("history-dir") {
+	config.history_dir = Some(dir.into());
+    }
+    if let Some(length) = matches.value_of("history-length") {
+	config.history_length = length.parse().expect("Invalid history length");
+    }
+    if let Some(page) = matches.value_of("history-page") {
+	config.history_page = page.parse().expect("Invalid history page size");
+    }
+    if let (Some(cert), Some(key)) = (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+	config.tls = Some(signalling::TlsFiles { cert: cert.into(), key: key.into() });
+    }
+
+    if let Some(path) = matches.value_of("token-secret-file") {
+	let secret = std::fs::read_to_string(path)?;
+	config.token_secret = Some(secret.trim().as_bytes().to_owned());
+    }
 
-    signalling::main(address)
+    signalling::main(config)
 }
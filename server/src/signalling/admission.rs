@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use super::limit::RateLimit;
+use super::Config;
+
+/// Limits how many connections each address may have open, and how often it may open them.
+pub struct Admission {
+    max_connections: Option<usize>,
+    /// Accepts allowed at once and the interval after, if limited
+    rate: Option<(u32, Duration)>,
+    clients: Mutex<Clients>,
+}
+
+#[derive(Default)]
+struct Clients {
+    by_addr: HashMap<IpAddr, Client>,
+    /// Addresses known after forgetting idle ones, to decide when to do so again
+    swept: usize,
+}
+
+struct Client {
+    open: usize,
+    accepts: Option<RateLimit>,
+}
+
+/// Held for as long as an admitted connection is open.
+pub struct Ticket<'a> {
+    admission: &'a Admission,
+    addr: IpAddr,
+}
+
+impl Client {
+    /// Whether the client has nothing open and could open as many connections as a new one.
+    fn idle(&self) -> bool {
+        self.open == 0 && self.accepts.as_ref().map_or(true, RateLimit::full)
+    }
+}
+
+impl Admission {
+    pub fn new(config: &Config) -> Self {
+        Admission {
+            max_connections: config.max_connections_per_ip,
+            rate: config
+                .accept_interval
+                .map(|interval| (config.accept_burst, interval)),
+            clients: Mutex::new(Clients::default()),
+        }
+    }
+
+    /// Counts a new connection from `addr`, or returns why it is refused.
+    pub fn admit(&self, addr: IpAddr) -> Result<Ticket<'_>, String> {
+        // Only counters are kept, which are consistent even if a panic interrupted an update
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        if clients.by_addr.len() >= 2 * clients.swept.max(512) {
+            clients.by_addr.retain(|_, client| !client.idle());
+            clients.swept = clients.by_addr.len();
+        }
+
+        let rate = self.rate;
+        let client = clients.by_addr.entry(addr).or_insert_with(|| Client {
+            open: 0,
+            accepts: rate.map(|(burst, interval)| RateLimit::new(burst, interval)),
+        });
+        if let Some(max) = self.max_connections.filter(|&max| client.open >= max) {
+            return Err(format!("{} connections already open", max));
+        }
+        if !client.accepts.as_mut().map_or(true, RateLimit::take) {
+            return Err("Connecting too often".to_owned());
+        }
+        client.open += 1;
+        Ok(Ticket {
+            admission: self,
+            addr,
+        })
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut clients = self
+            .admission
+            .clients
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(client) = clients.by_addr.get_mut(&self.addr) {
+            client.open -= 1;
+            if client.idle() {
+                clients.by_addr.remove(&self.addr);
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// May moderate the other peers in the room
+    Host,
+    Member,
+    Spectator,
+}
+
+/// Contents of a join token, of the form `<claims>.<signature>` where the claims are JSON and the
+/// signature an HMAC-SHA256 of them, both base64url-encoded.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Claims {
+    /// The user the token was issued to
+    pub sub: String,
+    pub room: String,
+    /// Expiry, in seconds since the Unix epoch
+    pub exp: u64,
+    pub role: Role,
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    Missing,
+    Malformed,
+    BadSignature,
+    Expired,
+    WrongRoom,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            TokenError::Missing => "Missing join token",
+            TokenError::Malformed => "Malformed join token",
+            TokenError::BadSignature => "Invalid join token signature",
+            TokenError::Expired => "Join token has expired",
+            TokenError::WrongRoom => "Join token is not valid for this room",
+        };
+        f.write_str(msg)
+    }
+}
+
+fn mac(secret: &[u8], payload: &[u8]) -> HmacSha256 {
+    // HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_varkey(secret).unwrap();
+    mac.update(payload);
+    mac
+}
+
+/// Issues a token for `claims`, signed with `secret`.
+pub fn sign(claims: &Claims, secret: &[u8]) -> Result<String, serde_json::Error> {
+    let payload = base64::encode_config(serde_json::to_vec(claims)?, base64::URL_SAFE_NO_PAD);
+    let signature = mac(secret, payload.as_bytes()).finalize().into_bytes();
+    Ok(format!(
+        "{}.{}",
+        payload,
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Checks a token's signature and expiry, and that it was issued for `room`.
+pub fn verify(token: &str, secret: &[u8], room: &str) -> Result<Claims, TokenError> {
+    let mut parts = token.splitn(2, '.');
+    let (payload, signature) = match (parts.next(), parts.next()) {
+        (Some(payload), Some(signature)) => (payload, signature),
+        _ => return Err(TokenError::Malformed),
+    };
+
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| TokenError::Malformed)?;
+    mac(secret, payload.as_bytes())
+        .verify(&signature)
+        .map_err(|_| TokenError::BadSignature)?;
+
+    let claims = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| TokenError::Malformed)?;
+    let claims = serde_json::from_slice::<Claims>(&claims).map_err(|_| TokenError::Malformed)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or(0);
+    if claims.exp <= now {
+        Err(TokenError::Expired)
+    } else if claims.room != room {
+        Err(TokenError::WrongRoom)
+    } else {
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn claims(room: &str, exp: u64) -> Claims {
+        Claims {
+            sub: "alice".to_owned(),
+            room: room.to_owned(),
+            exp,
+            role: Role::Member,
+        }
+    }
+
+    fn future() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600
+    }
+
+    /// Signs an arbitrary payload, to get past the signature check with invalid claims.
+    fn sign_raw(payload: &[u8]) -> String {
+        let payload = base64::encode_config(payload, base64::URL_SAFE_NO_PAD);
+        let signature = mac(SECRET, payload.as_bytes()).finalize().into_bytes();
+        let signature = base64::encode_config(signature, base64::URL_SAFE_NO_PAD);
+        format!("{}.{}", payload, signature)
+    }
+
+    #[test]
+    fn valid() {
+        let token = sign(&claims("lobby", future()), SECRET).unwrap();
+        let verified = verify(&token, SECRET, "lobby").unwrap();
+        assert_eq!(verified.sub, "alice");
+        assert_eq!(verified.role, Role::Member);
+    }
+
+    #[test]
+    fn bad_signature() {
+        let token = sign(&claims("lobby", future()), b"other secret").unwrap();
+        let result = verify(&token, SECRET, "lobby");
+        assert!(matches!(result, Err(TokenError::BadSignature)));
+
+        // Claims swapped out from under a valid signature
+        let (_, signature) = token.split_at(token.find('.').unwrap());
+        let forged = sign(&claims("lobby", future()), SECRET).unwrap();
+        let (payload, _) = forged.split_at(forged.find('.').unwrap());
+        let result = verify(&format!("{}{}", payload, signature), SECRET, "lobby");
+        assert!(matches!(result, Err(TokenError::BadSignature)));
+    }
+
+    #[test]
+    fn expired() {
+        let token = sign(&claims("lobby", 1), SECRET).unwrap();
+        let result = verify(&token, SECRET, "lobby");
+        assert!(matches!(result, Err(TokenError::Expired)));
+    }
+
+    #[test]
+    fn wrong_room() {
+        let token = sign(&claims("lobby", future()), SECRET).unwrap();
+        let result = verify(&token, SECRET, "other");
+        assert!(matches!(result, Err(TokenError::WrongRoom)));
+    }
+
+    #[test]
+    fn malformed() {
+        for token in &["", "no-signature", "not base64!.not base64!"] {
+            let result = verify(token, SECRET, "lobby");
+            assert!(matches!(result, Err(TokenError::Malformed)), "{:?}", token);
+        }
+
+        for payload in &[&b"not json"[..], b"{\"sub\":\"alice\"}"] {
+            let result = verify(&sign_raw(payload), SECRET, "lobby");
+            assert!(matches!(result, Err(TokenError::Malformed)));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let token = sign(&claims("lobby", future()), SECRET).unwrap();
+        for len in 0..token.len() {
+            let result = verify(&token[..len], SECRET, "lobby");
+            assert!(
+                matches!(
+                    result,
+                    Err(TokenError::Malformed) | Err(TokenError::BadSignature)
+                ),
+                "{:?}",
+                &token[..len]
+            );
+        }
+    }
+}
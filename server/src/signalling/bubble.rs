@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::grid::Grid;
+use super::message::{BubbleId, PeerId, Pos};
+
+/// Groups peers into conversation bubbles: peers within talking distance of each other, directly
+/// or through others in between. Peers on their own are not in a bubble.
+#[derive(Debug)]
+pub struct Bubbles {
+    radius: f32,
+    grid: Grid,
+    next_id: BubbleId,
+    of: HashMap<PeerId, BubbleId>,
+    members: HashMap<BubbleId, HashSet<PeerId>>,
+    /// Peers whose bubble may have changed since the last update
+    pending: HashSet<PeerId>,
+    /// Bubbles a peer has left the room from since the last update
+    left: HashSet<BubbleId>,
+}
+
+impl Bubbles {
+    pub fn new(radius: f32) -> Self {
+        Bubbles {
+            radius,
+            grid: Grid::new(radius),
+            next_id: 0,
+            of: HashMap::new(),
+            members: HashMap::new(),
+            pending: HashSet::new(),
+            left: HashSet::new(),
+        }
+    }
+
+    /// The bubble a peer is in, if it is talking to anyone.
+    pub fn of(&self, id: PeerId) -> Option<BubbleId> {
+        self.of.get(&id).copied()
+    }
+
+    pub fn members(&self, bubble: BubbleId) -> impl Iterator<Item = PeerId> + '_ {
+        self.members.get(&bubble).into_iter().flatten().copied()
+    }
+
+    /// Every bubble, with its members.
+    pub fn all(&self) -> impl Iterator<Item = (BubbleId, &HashSet<PeerId>)> {
+        self.members
+            .iter()
+            .map(|(&bubble, members)| (bubble, members))
+    }
+
+    /// Records a peer that has joined or moved, to be regrouped on the next update.
+    pub fn moved(&mut self, id: PeerId, pos: Pos) {
+        self.grid.insert(id, pos);
+        self.pending.insert(id);
+    }
+
+    pub fn remove(&mut self, id: PeerId) {
+        self.grid.remove(id);
+        self.pending.remove(&id);
+        if let Some(bubble) = self.of.remove(&id) {
+            let members = self.members.get_mut(&bubble).unwrap();
+            members.remove(&id);
+            // The bubble may have split up without it
+            self.pending.extend(members.iter().copied());
+            self.left.insert(bubble);
+        }
+    }
+
+    /// Finds everyone within talking distance of `start`, through any number of others.
+    fn component(&self, start: PeerId, visited: &mut HashSet<PeerId>) -> HashSet<PeerId> {
+        let mut component = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+        visited.insert(start);
+        while let Some(id) = queue.pop_front() {
+            component.insert(id);
+            let pos = match self.grid.get(id) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            for other in self.grid.query(pos, self.radius) {
+                if visited.insert(other) {
+                    queue.push_back(other);
+                }
+            }
+        }
+        component
+    }
+
+    /// Regroups the peers that moved or left since the last update, returning the bubbles whose
+    /// members changed. Bubbles that dissolved are returned with no members.
+    pub fn update(&mut self) -> Vec<(BubbleId, Vec<PeerId>)> {
+        // Everyone in the same bubble as a peer that moved may end up somewhere else
+        let mut seeds = std::mem::take(&mut self.pending);
+        for id in seeds.clone() {
+            if let Some(bubble) = self.of(id) {
+                seeds.extend(self.members[&bubble].iter().copied());
+            }
+        }
+
+        let mut visited = HashSet::new();
+        let mut components = Vec::new();
+        for &id in &seeds {
+            if !visited.contains(&id) {
+                components.push(self.component(id, &mut visited));
+            }
+        }
+
+        // Take everyone regrouped out of their old bubble, remembering how it was
+        let mut before = HashMap::new();
+        let mut previous = HashMap::new();
+        for &id in components.iter().flatten() {
+            if let Some(bubble) = self.of.remove(&id) {
+                let members = self.members.get_mut(&bubble).unwrap();
+                before.entry(bubble).or_insert_with(|| members.clone());
+                members.remove(&id);
+                previous.insert(id, bubble);
+            }
+        }
+
+        let left = std::mem::take(&mut self.left);
+        let mut touched = before.keys().chain(&left).copied().collect::<HashSet<_>>();
+        for component in components.into_iter().filter(|c| c.len() > 1) {
+            // Keeps the identity of the old bubble most of the component came from, unless it
+            // has already been reused
+            let mut counts = HashMap::new();
+            for bubble in component.iter().filter_map(|id| previous.get(id)) {
+                *counts.entry(*bubble).or_insert(0) += 1;
+            }
+            let reused = counts
+                .into_iter()
+                .filter(|(bubble, _)| self.members.get(bubble).map_or(true, HashSet::is_empty))
+                .max_by_key(|&(bubble, count)| (count, std::cmp::Reverse(bubble)))
+                .map(|(bubble, _)| bubble);
+            let bubble = reused.unwrap_or_else(|| {
+                self.next_id += 1;
+                self.next_id
+            });
+
+            for &id in &component {
+                self.of.insert(id, bubble);
+            }
+            self.members.insert(bubble, component);
+            touched.insert(bubble);
+        }
+        self.members.retain(|_, members| !members.is_empty());
+
+        touched
+            .into_iter()
+            .filter(|bubble| {
+                left.contains(bubble) || self.members.get(bubble) != before.get(bubble)
+            })
+            .map(|bubble| {
+                let members = self.members.get(&bubble).into_iter().flatten();
+                (bubble, members.copied().collect())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place(bubbles: &mut Bubbles, peers: &[(PeerId, f32)]) {
+        for &(id, x) in peers {
+            bubbles.moved(id, Pos { x, y: 0.0 });
+        }
+    }
+
+    /// The changed bubbles, in order and with their members sorted.
+    fn update(bubbles: &mut Bubbles) -> Vec<(BubbleId, Vec<PeerId>)> {
+        let mut changed = bubbles.update();
+        for (_, members) in &mut changed {
+            members.sort();
+        }
+        changed.sort();
+        changed
+    }
+
+    #[test]
+    fn form() {
+        let mut bubbles = Bubbles::new(10.0);
+        place(&mut bubbles, &[(1, 0.0), (2, 8.0), (3, 16.0), (4, 100.0)]);
+        // Peers chain together through those in between, but not to those on their own
+        assert_eq!(update(&mut bubbles), [(1, vec![1, 2, 3])]);
+        assert_eq!(bubbles.of(4), None);
+        // Nothing changed since
+        assert_eq!(update(&mut bubbles), []);
+    }
+
+    #[test]
+    fn split() {
+        let mut bubbles = Bubbles::new(10.0);
+        place(
+            &mut bubbles,
+            &[(1, 0.0), (2, 8.0), (3, 16.0), (4, 24.0), (5, 32.0)],
+        );
+        update(&mut bubbles);
+
+        place(&mut bubbles, &[(3, 200.0)]);
+        let changed = update(&mut bubbles);
+        // Either half may keep the old bubble, the other is given a new one
+        let ids = changed.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids, [1, 2]);
+        let mut halves = changed
+            .into_iter()
+            .map(|(_, members)| members)
+            .collect::<Vec<_>>();
+        halves.sort();
+        assert_eq!(halves, [vec![1, 2], vec![4, 5]]);
+        assert_eq!(bubbles.of(3), None);
+    }
+
+    #[test]
+    fn merge() {
+        let mut bubbles = Bubbles::new(10.0);
+        place(&mut bubbles, &[(1, 0.0), (2, 5.0), (3, 100.0), (4, 105.0)]);
+        update(&mut bubbles);
+        let (left, right) = (bubbles.of(1).unwrap(), bubbles.of(3).unwrap());
+        assert_ne!(left, right);
+
+        place(&mut bubbles, &[(3, 10.0), (4, 15.0)]);
+        // On a tie the older bubble is kept, and the other dissolves
+        let (kept, dissolved) = (left.min(right), left.max(right));
+        assert_eq!(
+            update(&mut bubbles),
+            [(kept, vec![1, 2, 3, 4]), (dissolved, vec![])]
+        );
+        assert_eq!(bubbles.all().count(), 1);
+    }
+
+    #[test]
+    fn leave() {
+        let mut bubbles = Bubbles::new(10.0);
+        place(&mut bubbles, &[(1, 0.0), (2, 5.0), (3, 10.0)]);
+        update(&mut bubbles);
+
+        bubbles.remove(3);
+        assert_eq!(update(&mut bubbles), [(1, vec![1, 2])]);
+        // With one peer left it is talking to nobody
+        bubbles.remove(2);
+        assert_eq!(update(&mut bubbles), [(1, vec![])]);
+        assert_eq!(bubbles.of(1), None);
+        assert_eq!(bubbles.members(1).count(), 0);
+    }
+}
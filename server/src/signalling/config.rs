@@ -0,0 +1,170 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::map::Map;
+
+/// Settings for the signalling server.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub address: String,
+    /// Path WebSocket connections are accepted on, with the rooms below it
+    pub signalling_path: String,
+    /// Directory of files to serve over plain HTTP, such as the built client
+    pub static_dir: Option<PathBuf>,
+    /// Path the files in `static_dir` are served under
+    pub static_path: String,
+    /// Messages a peer may have waiting to be sent before it is disconnected as too slow
+    pub outbox_limit: usize,
+    /// Origins browsers may connect from, such as `https://example.com`. Any origin is allowed if
+    /// unset. Clients that send no origin are allowed too, as browsers always send one.
+    pub allowed_origins: Option<Vec<String>>,
+    /// Reverse proxies trusted to give the address of the client they connect for in
+    /// `X-Forwarded-For`. Connections from anywhere else are taken to come from where they do.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Connections each address may have open at once, if limited. Behind a proxy that is not
+    /// trusted, every client has the proxy's address.
+    pub max_connections_per_ip: Option<usize>,
+    /// Connections each address may open at once, before being limited to `accept_interval`
+    pub accept_burst: u32,
+    /// How often each address may open a connection once it has used up its burst, if limited
+    pub accept_interval: Option<Duration>,
+    /// Key for verifying join tokens, if clients must present one to connect
+    pub token_secret: Option<Vec<u8>>,
+    /// How long a peer that lost its connection keeps its place in the room, to resume its session
+    pub resume_grace: Duration,
+    /// How often peers are pinged to check their connection is alive
+    pub ping_interval: Duration,
+    /// How long a peer may go without sending anything, including pongs, before its connection
+    /// is considered lost
+    pub idle_timeout: Duration,
+    /// Malformed messages a peer may send at once before it is disconnected, and likewise
+    /// messages refused for going over a rate limit, which are counted apart
+    pub max_protocol_errors: u32,
+    /// How often one of a peer's malformed or rate limited messages is forgiven
+    pub protocol_error_interval: Duration,
+    /// Largest message peers may send, in bytes, above which they are disconnected outright
+    pub max_message_size: usize,
+    /// Largest SDP or ICE candidate message peers may send, in bytes
+    pub max_relay_size: usize,
+    /// Largest `Move` or `SetRadius` message peers may send, in bytes
+    pub max_movement_size: usize,
+    /// Largest message of any other type peers may send, in bytes
+    pub max_control_size: usize,
+    /// SDP and ICE candidate messages peers may send at once, before being limited to
+    /// `relay_interval`
+    pub relay_burst: u32,
+    /// How often peers may relay SDP and ICE candidates once they have used up their burst
+    pub relay_interval: Duration,
+    /// Moves peers may send at once, before being limited to `movement_interval`
+    pub movement_burst: u32,
+    /// How often peers may move once they have used up their burst
+    pub movement_interval: Duration,
+    /// Messages of any other type peers may send at once, before being limited to
+    /// `control_interval`
+    pub control_burst: u32,
+    /// How often peers may send other messages once they have used up their burst
+    pub control_interval: Duration,
+    /// Distance within which peers come into range, unless they pick their own radius
+    pub interest_radius: f32,
+    /// The largest radius peers may pick
+    pub max_interest_radius: f32,
+    /// How much further than their radius peers must move apart to go out of range
+    pub interest_hysteresis: f32,
+    /// How often moves are sent out, batched into one message for each peer
+    pub tick: Duration,
+    /// Positions are rounded to multiples of this before being sent
+    pub position_quantum: f32,
+    /// Send positions as changes since the last one sent for each peer, rather than in full
+    pub position_deltas: bool,
+    /// Layout of rooms
+    pub map: Map,
+    /// How fast peers may move, in units per second, if limited
+    pub max_speed: Option<f32>,
+    /// Peers this close are talking to each other, and in the same conversation bubble
+    pub talk_radius: f32,
+    /// Longest chat message peers may send, in characters
+    pub max_chat_length: usize,
+    /// Chat messages peers may send at once, before being limited to `chat_interval`
+    pub chat_burst: u32,
+    /// How often peers may send chat messages once they have used up their burst
+    pub chat_interval: Duration,
+    /// Changes to only their speaking flag peers may send at once, before being limited to
+    /// `speaking_interval`
+    pub speaking_burst: u32,
+    /// How often changes to only the speaking flag are passed on once peers have used up their
+    /// burst. Later changes are held back until then, rather than dropped.
+    pub speaking_interval: Duration,
+    /// Longest display name peers may pick, in characters
+    pub max_name_length: usize,
+    /// Largest metadata peers may attach to their profile, in bytes of JSON
+    pub max_metadata_size: usize,
+    /// Where room-wide chat is saved, one file per room. Kept only while a room is open if unset.
+    pub history_dir: Option<PathBuf>,
+    /// Room-wide chat messages kept for each room
+    pub history_length: usize,
+    /// Messages sent to peers when they join, and the most they may ask for at once
+    pub history_page: usize,
+    /// Certificate to serve TLS with. Connections are plain TCP if unset.
+    pub tls: Option<TlsFiles>,
+}
+
+/// PEM files holding a certificate chain and its private key.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "localhost:4000".to_owned(),
+            signalling_path: "/".to_owned(),
+            static_dir: None,
+            static_path: "/".to_owned(),
+            outbox_limit: 256,
+            allowed_origins: None,
+            trusted_proxies: Vec::new(),
+            max_connections_per_ip: None,
+            accept_burst: 10,
+            accept_interval: None,
+            token_secret: None,
+            resume_grace: Duration::from_secs(30),
+            ping_interval: Duration::from_secs(15),
+            idle_timeout: Duration::from_secs(45),
+            max_protocol_errors: 20,
+            protocol_error_interval: Duration::from_secs(10),
+            max_message_size: 64 * 1024,
+            max_relay_size: 32 * 1024,
+            max_movement_size: 256,
+            max_control_size: 8 * 1024,
+            relay_burst: 100,
+            relay_interval: Duration::from_millis(10),
+            movement_burst: 30,
+            movement_interval: Duration::from_millis(15),
+            control_burst: 20,
+            control_interval: Duration::from_millis(100),
+            interest_radius: 600.0,
+            max_interest_radius: 2000.0,
+            interest_hysteresis: 100.0,
+            tick: Duration::from_millis(50),
+            position_quantum: 1.0,
+            position_deltas: false,
+            map: Map::default(),
+            max_speed: None,
+            talk_radius: 150.0,
+            max_chat_length: 1000,
+            chat_burst: 5,
+            chat_interval: Duration::from_secs(1),
+            speaking_burst: 4,
+            speaking_interval: Duration::from_millis(250),
+            max_name_length: 64,
+            max_metadata_size: 1024,
+            history_dir: None,
+            history_length: 1000,
+            history_page: 50,
+            tls: None,
+        }
+    }
+}
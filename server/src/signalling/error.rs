@@ -1,3 +1,6 @@
+use std::path::PathBuf;
+
+use tokio_rustls::rustls;
 use tokio_tungstenite::tungstenite;
 
 #[derive(Debug)]
@@ -5,7 +8,11 @@ pub enum Error {
     IO(std::io::Error),
     WebSocket(tungstenite::Error),
     JSON(serde_json::error::Error),
+    TLS(rustls::TLSError),
+    /// A PEM file holds no certificates or keys that could be read
+    InvalidCertificate(PathBuf),
     Poison,
+    RoomClosed,
 }
 
 impl From<std::io::Error> for Error {
@@ -20,6 +27,12 @@ impl From<tungstenite::Error> for Error {
     }
 }
 
+impl From<rustls::TLSError> for Error {
+    fn from(e: rustls::TLSError) -> Self {
+        Error::TLS(e)
+    }
+}
+
 impl<T> From<std::sync::PoisonError<T>> for Error {
     fn from(_: std::sync::PoisonError<T>) -> Self {
         Error::Poison
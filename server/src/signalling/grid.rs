@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use super::message::{PeerId, Pos};
+
+type Cell = (i32, i32);
+
+/// Uniform grid of peer positions, so peers near a point can be found without checking everyone
+/// in the room.
+#[derive(Debug)]
+pub struct Grid {
+    size: f32,
+    cells: HashMap<Cell, HashMap<PeerId, Pos>>,
+    index: HashMap<PeerId, Cell>,
+}
+
+impl Grid {
+    /// Creates a grid of square cells `size` wide. Queries are cheapest with a radius of about
+    /// the cell size.
+    pub fn new(size: f32) -> Self {
+        Grid {
+            size: size.max(1.0),
+            cells: HashMap::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, pos: Pos) -> Cell {
+        (
+            (pos.x / self.size).floor() as i32,
+            (pos.y / self.size).floor() as i32,
+        )
+    }
+
+    /// Adds a peer, or moves it if it is already in the grid.
+    pub fn insert(&mut self, id: PeerId, pos: Pos) {
+        let cell = self.cell(pos);
+        if let Some(old) = self.index.insert(id, cell) {
+            if old != cell {
+                self.remove_from(old, id);
+            }
+        }
+        self.cells.entry(cell).or_default().insert(id, pos);
+    }
+
+    pub fn get(&self, id: PeerId) -> Option<Pos> {
+        let cell = self.index.get(&id)?;
+        self.cells.get(cell)?.get(&id).copied()
+    }
+
+    pub fn remove(&mut self, id: PeerId) {
+        if let Some(cell) = self.index.remove(&id) {
+            self.remove_from(cell, id);
+        }
+    }
+
+    fn remove_from(&mut self, cell: Cell, id: PeerId) {
+        if let Some(peers) = self.cells.get_mut(&cell) {
+            peers.remove(&id);
+            if peers.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    /// Finds the peers within `radius` of `pos`.
+    pub fn query(&self, pos: Pos, radius: f32) -> Vec<PeerId> {
+        let (min_x, min_y) = self.cell(Pos {
+            x: pos.x - radius,
+            y: pos.y - radius,
+        });
+        let (max_x, max_y) = self.cell(Pos {
+            x: pos.x + radius,
+            y: pos.y + radius,
+        });
+
+        let mut found = Vec::new();
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                let peers = match self.cells.get(&(x, y)) {
+                    Some(peers) => peers,
+                    None => continue,
+                };
+                found.extend(
+                    peers
+                        .iter()
+                        .filter(|(_, p)| (p.x - pos.x).hypot(p.y - pos.y) <= radius)
+                        .map(|(&id, _)| id),
+                );
+            }
+        }
+        found
+    }
+}
@@ -0,0 +1,181 @@
+use std::net::IpAddr;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::{header, HeaderValue, StatusCode};
+use tungstenite::protocol::WebSocketConfig;
+
+use super::auth::{self, Claims, TokenError};
+use super::http;
+use super::message::PeerId;
+use super::protocol::Protocol;
+use super::{Config, Error};
+
+/// Room joined by clients connecting to the bare signalling path.
+const DEFAULT_ROOM: &str = "default";
+const MAX_ROOM_NAME: usize = 64;
+
+/// What a client asked for when connecting, checked before it is upgraded to a WebSocket.
+pub struct Join {
+    pub room: String,
+    /// Verified token claims, if the server requires tokens
+    pub claims: Option<Claims>,
+    /// Session to resume and its secret, from the `session` and `resume` query parameters
+    pub resume: Option<(PeerId, String)>,
+    pub protocol: Protocol,
+    /// Whether to join as a spectator, from the `spectate` query parameter
+    pub spectate: bool,
+    /// Address the client connected from
+    pub addr: IpAddr,
+}
+
+/// Maps a request path below the signalling path of the form `/room/<name>` to a room name, `/`
+/// is the default room.
+fn room_name(path: &str) -> Option<&str> {
+    let name = match path.trim_end_matches('/') {
+        "" => DEFAULT_ROOM,
+        path => path.strip_prefix("/room/")?,
+    };
+
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid && !name.is_empty() && name.len() <= MAX_ROOM_NAME {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+fn query_param<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.uri()
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|param| {
+            let (key, value) = param.split_at(param.find('=')?);
+            if key == name {
+                Some(&value[1..])
+            } else {
+                None
+            }
+        })
+}
+
+/// Finds a join token in the `token` query parameter, or a bearer `Authorization` header.
+fn token(req: &Request) -> Option<&str> {
+    query_param(req, "token").or_else(|| {
+        req.headers()
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")
+    })
+}
+
+fn reject(status: StatusCode, reason: String) -> ErrorResponse {
+    let mut resp = ErrorResponse::new(Some(reason));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Picks the protocol version from the subprotocols the client offered, returning the
+/// subprotocol to confirm.
+fn protocol(req: &Request) -> Result<(Option<&'static str>, Protocol), ErrorResponse> {
+    let offered = match req.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
+        Some(offered) => offered.to_str().unwrap_or(""),
+        None => return Ok((None, Protocol::default())),
+    };
+    Protocol::negotiate(offered)
+        .map(|(name, protocol)| (Some(name), protocol))
+        .ok_or_else(|| {
+            reject(
+                StatusCode::BAD_REQUEST,
+                "Unsupported protocol version".to_owned(),
+            )
+        })
+}
+
+fn check(
+    req: &Request,
+    addr: IpAddr,
+    config: &Config,
+) -> Result<(Join, Option<&'static str>), ErrorResponse> {
+    if let (Some(allowed), Some(origin)) =
+        (&config.allowed_origins, req.headers().get(header::ORIGIN))
+    {
+        let origin = origin.to_str().unwrap_or("");
+        let allowed = allowed
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin));
+        if !allowed {
+            let reason = format!("Origin {:?} is not allowed", origin);
+            return Err(reject(StatusCode::FORBIDDEN, reason));
+        }
+    }
+
+    let room = http::within(&config.signalling_path, req.uri().path())
+        .and_then(room_name)
+        .ok_or_else(|| reject(StatusCode::NOT_FOUND, "Unknown room".to_owned()))?;
+
+    let claims = match &config.token_secret {
+        Some(secret) => token(req)
+            .ok_or(TokenError::Missing)
+            .and_then(|token| auth::verify(token, secret, room))
+            .map(Some)
+            .map_err(|e| reject(StatusCode::UNAUTHORIZED, e.to_string()))?,
+        None => None,
+    };
+
+    let session = query_param(req, "session").and_then(|id| id.parse().ok());
+    let resume = session.zip(query_param(req, "resume").map(str::to_owned));
+    let (subprotocol, protocol) = protocol(req)?;
+    let spectate = matches!(query_param(req, "spectate"), Some("1") | Some("true"));
+
+    let join = Join {
+        room: room.to_owned(),
+        claims,
+        resume,
+        protocol,
+        spectate,
+        addr,
+    };
+    Ok((join, subprotocol))
+}
+
+pub async fn accept<S>(
+    s: S,
+    addr: IpAddr,
+    config: &Config,
+) -> Result<(Join, WebSocketStream<S>), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut join = None;
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(config.max_message_size),
+        max_frame_size: Some(config.max_message_size),
+        ..WebSocketConfig::default()
+    };
+    let callback = |req: &Request, mut resp: Response| {
+        let (checked, subprotocol) = check(req, addr, config).map_err(|resp| {
+            let reason = resp.body().as_deref().unwrap_or("");
+            eprintln!("Refused connection from {}: {}", addr, reason);
+            resp
+        })?;
+        if let Some(subprotocol) = subprotocol {
+            resp.headers_mut().insert(
+                header::SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(subprotocol),
+            );
+        }
+        join = Some(checked);
+        Ok(resp)
+    };
+    let ws = tokio_tungstenite::accept_hdr_async_with_config(s, callback, Some(ws_config)).await?;
+
+    // The callback has always run if the handshake succeeded
+    Ok((join.unwrap(), ws))
+}
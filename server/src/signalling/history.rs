@@ -0,0 +1,379 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc;
+use futures::{FutureExt, StreamExt};
+use tokio::task;
+
+use super::message::{HistoryEntry, PeerId};
+use super::Error;
+
+/// The latest room-wide chat messages of a room, saved to a file if there is somewhere to put
+/// them.
+pub struct History {
+    /// How many messages are kept
+    length: usize,
+    entries: VecDeque<HistoryEntry>,
+    next_id: u64,
+    /// Changes to the file, made by a task of its own so the room never waits on the disk
+    log: Option<mpsc::UnboundedSender<Change>>,
+    /// Lines in the file, which is rewritten with only the kept messages once it grows too long
+    lines: usize,
+}
+
+/// File that messages are appended to, one JSON object per line.
+struct Log {
+    path: PathBuf,
+    file: File,
+}
+
+enum Change {
+    Append(HistoryEntry),
+    Rewrite(Vec<HistoryEntry>),
+}
+
+/// The task writing each room's file, so a room that reopens waits until the file has been
+/// written by the last one before loading it.
+#[derive(Clone, Default)]
+pub struct Writers(Arc<Mutex<HashMap<String, task::JoinHandle<()>>>>);
+
+/// Names a room's file after the room, escaping anything that might not be safe in a path.
+fn file_name(room: &str) -> String {
+    let mut name = String::with_capacity(room.len() + 6);
+    for b in room.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+            name.push(b as char);
+        } else {
+            name.push_str(&format!("%{:02X}", b));
+        }
+    }
+    name.push_str(".jsonl");
+    name
+}
+
+impl Log {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok(Log { path, file })
+    }
+
+    fn append(&mut self, entry: &HistoryEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Replaces the file with one holding only `entries`.
+    fn rewrite<'a>(
+        &mut self,
+        entries: impl Iterator<Item = &'a HistoryEntry>,
+    ) -> Result<(), Error> {
+        // Named uniquely so that a rewrite never clashes with one left behind by a crash
+        let tmp = (self.path).with_extension(format!("jsonl.{:016x}.tmp", rand::random::<u64>()));
+        let mut file = io::BufWriter::new(File::create(&tmp)?);
+        for entry in entries {
+            serde_json::to_writer(&mut file, entry)?;
+            file.write_all(b"\n")?;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        *self = Log::open(self.path.clone())?;
+        Ok(())
+    }
+
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Append(entry) => {
+                if let Err(e) = self.append(&entry) {
+                    eprintln!("Could not save chat message to {:?}: {:?}", self.path, e);
+                }
+            }
+            Change::Rewrite(entries) => {
+                if let Err(e) = self.rewrite(entries.iter()) {
+                    eprintln!("Could not compact chat history {:?}: {:?}", self.path, e);
+                }
+            }
+        }
+    }
+}
+
+/// Makes changes to the file in the order they are sent, on a blocking thread. Changes sent
+/// while one is being made are made together.
+async fn write(
+    mut log: Log,
+    mut rx: mpsc::UnboundedReceiver<Change>,
+    room: String,
+    writers: Writers,
+) {
+    while let Some(change) = rx.next().await {
+        let mut changes = vec![change];
+        while let Some(Some(change)) = rx.next().now_or_never() {
+            changes.push(change);
+        }
+        let path = log.path.clone();
+        log = match task::spawn_blocking(move || {
+            changes.into_iter().for_each(|change| log.apply(change));
+            log
+        })
+        .await
+        {
+            Ok(log) => log,
+            Err(e) => {
+                eprintln!("Stopped saving chat history to {:?}: {}", path, e);
+                break;
+            }
+        };
+    }
+    // A room reopening since has already taken this task's place
+    if let Ok(mut writers) = writers.0.lock() {
+        writers.remove(&room);
+    }
+}
+
+impl History {
+    /// Creates a history that is lost when the room closes.
+    pub fn new(length: usize) -> Self {
+        History {
+            length,
+            entries: VecDeque::new(),
+            next_id: 0,
+            log: None,
+            lines: 0,
+        }
+    }
+
+    /// Loads a room's history from `dir`, where new messages will be saved, once the messages
+    /// of the room's last run have been.
+    pub async fn open(
+        dir: PathBuf,
+        room: String,
+        length: usize,
+        writers: &Writers,
+    ) -> Result<Self, Error> {
+        let previous = writers.0.lock()?.remove(&room);
+        if let Some(previous) = previous {
+            // Fails only if the writer panicked, after which the file is as it left it
+            let _ = previous.await;
+        }
+
+        let name = room.clone();
+        let (mut history, log) = task::spawn_blocking(move || History::load(&dir, &name, length))
+            .await
+            .map_err(io::Error::from)??;
+        let (tx, rx) = mpsc::unbounded();
+        let writer = tokio::spawn(write(log, rx, room.clone(), writers.clone()));
+        writers.0.lock()?.insert(room, writer);
+        history.log = Some(tx);
+        Ok(history)
+    }
+
+    /// Reads a room's history, compacting the file if needed, and opens it for new messages.
+    fn load(dir: &Path, room: &str, length: usize) -> Result<(Self, Log), Error> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(file_name(room));
+
+        let mut history = History::new(length);
+        let mut lines = 0;
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    lines += 1;
+                    // The last line may have been cut short by a crash
+                    match serde_json::from_str::<HistoryEntry>(&line) {
+                        Ok(entry) => history.keep(entry),
+                        Err(e) => eprintln!("Skipping chat history line in {:?}: {}", path, e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut log = Log::open(path)?;
+        if lines > history.entries.len() {
+            log.rewrite(history.entries.iter())?;
+        }
+        history.lines = history.entries.len();
+        Ok((history, log))
+    }
+
+    fn keep(&mut self, entry: HistoryEntry) {
+        self.next_id = self.next_id.max(entry.id + 1);
+        self.entries.push_back(entry);
+        while self.entries.len() > self.length {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Records a message, returning its ID.
+    pub fn push(&mut self, from: PeerId, text: String, time: u64) -> u64 {
+        let entry = HistoryEntry {
+            id: self.next_id,
+            from,
+            text,
+            time,
+        };
+        let id = entry.id;
+
+        if let Some(log) = &self.log {
+            // Fails only if the writer has stopped, which it reports itself
+            let _ = log.unbounded_send(Change::Append(entry.clone()));
+            self.lines += 1;
+        }
+        self.keep(entry);
+
+        // Rewriting only once the file is twice as long as needed keeps it mostly append-only
+        let compact = self.lines >= 2 * self.entries.len().max(1);
+        if let Some(log) = self.log.as_ref().filter(|_| compact) {
+            let entries = self.entries.iter().cloned().collect();
+            let _ = log.unbounded_send(Change::Rewrite(entries));
+            self.lines = self.entries.len();
+        }
+        id
+    }
+
+    /// Up to `limit` of the latest messages from before the one with ID `before`, oldest first,
+    /// and whether there are any older ones.
+    pub fn page(&self, before: Option<u64>, limit: usize) -> (Vec<HistoryEntry>, bool) {
+        let end = match before {
+            Some(before) => self.entries.partition_point(|entry| entry.id < before),
+            None => self.entries.len(),
+        };
+        let start = end.saturating_sub(limit);
+        let page = self.entries.range(start..end).cloned().collect();
+        (page, start > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime;
+
+    use super::*;
+
+    /// A directory of its own for each test, as they run in parallel.
+    fn dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("webrtc-history-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(id: u64) -> String {
+        let entry = HistoryEntry {
+            id,
+            from: 1,
+            text: format!("message {}", id),
+            time: id,
+        };
+        serde_json::to_string(&entry).unwrap() + "\n"
+    }
+
+    fn ids(entries: &[HistoryEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.id).collect()
+    }
+
+    fn lines(dir: &Path) -> usize {
+        let file = fs::read_to_string(dir.join(file_name("room"))).unwrap();
+        file.lines().count()
+    }
+
+    async fn open(dir: &Path, length: usize, writers: &Writers) -> History {
+        let (dir, room) = (dir.to_owned(), "room".to_owned());
+        History::open(dir, room, length, writers).await.unwrap()
+    }
+
+    /// Runs a test on a runtime of its own, which the history's writer needs to keep running.
+    fn run<F: std::future::Future<Output = ()>>(test: F) {
+        let rt = runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(test);
+    }
+
+    #[test]
+    fn page() {
+        let mut history = History::new(10);
+        for time in 0..5 {
+            history.push(1, "message".to_owned(), time);
+        }
+        let page = |before, limit| {
+            let (entries, more) = history.page(before, limit);
+            (ids(&entries), more)
+        };
+        assert_eq!(page(None, 2), (vec![3, 4], true));
+        assert_eq!(page(Some(3), 2), (vec![1, 2], true));
+        assert_eq!(page(Some(2), 2), (vec![0, 1], false));
+        assert_eq!(page(Some(1), 2), (vec![0], false));
+        assert_eq!(page(Some(0), 2), (vec![], false));
+        assert_eq!(page(Some(100), 10), (vec![0, 1, 2, 3, 4], false));
+    }
+
+    #[test]
+    fn kept() {
+        let mut history = History::new(3);
+        for time in 0..5 {
+            assert_eq!(history.push(1, "message".to_owned(), time), time);
+        }
+        assert_eq!(ids(&history.page(None, 10).0), [2, 3, 4]);
+    }
+
+    #[test]
+    fn truncated() {
+        let dir = dir("truncated");
+        fs::create_dir_all(&dir).unwrap();
+        let cut = &entry(2)[..10];
+        fs::write(dir.join(file_name("room")), entry(0) + &entry(1) + cut).unwrap();
+
+        run(async {
+            let mut history = open(&dir, 10, &Writers::default()).await;
+            assert_eq!(ids(&history.page(None, 10).0), [0, 1]);
+            assert_eq!(history.push(1, "message".to_owned(), 2), 2);
+            // The broken line is dropped from the file when it is loaded
+            assert_eq!(lines(&dir), 2);
+        });
+    }
+
+    #[test]
+    fn compact_on_load() {
+        let dir = dir("compact_on_load");
+        fs::create_dir_all(&dir).unwrap();
+        let file = (0..5).map(entry).collect::<String>();
+        fs::write(dir.join(file_name("room")), file).unwrap();
+
+        run(async {
+            let history = open(&dir, 3, &Writers::default()).await;
+            assert_eq!(ids(&history.page(None, 10).0), [2, 3, 4]);
+            assert_eq!(lines(&dir), 3);
+        });
+    }
+
+    #[test]
+    fn compact_on_push() {
+        let dir = dir("compact_on_push");
+        let writers = Writers::default();
+        run(async {
+            let mut history = open(&dir, 2, &writers).await;
+            for time in 0..3 {
+                history.push(1, "message".to_owned(), time);
+            }
+            drop(history);
+            // Reopening waits for the last writer, so sees every message it was sent
+            let mut history = open(&dir, 2, &writers).await;
+            assert_eq!(ids(&history.page(None, 10).0), [1, 2]);
+
+            // The file is rewritten once it holds twice as many messages as are kept
+            assert_eq!(history.push(1, "message".to_owned(), 3), 3);
+            assert_eq!(history.push(1, "message".to_owned(), 4), 4);
+            drop(history);
+            let writer = writers.0.lock().unwrap().remove("room");
+            if let Some(writer) = writer {
+                writer.await.unwrap();
+            }
+            assert_eq!(lines(&dir), 2);
+        });
+    }
+}
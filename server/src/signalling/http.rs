@@ -0,0 +1,309 @@
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+
+use super::room::Rooms;
+use super::{Config, Error};
+
+/// Longest request head read, in bytes, before the request is rejected.
+const MAX_HEAD: usize = 8192;
+const MAX_HEADERS: usize = 32;
+
+/// What a request is for.
+pub enum Route {
+    /// A WebSocket upgrade on the signalling path, left to the handshake
+    Upgrade,
+    Page(Page),
+}
+
+/// Anything answered with a plain HTTP response.
+pub enum Page {
+    Health,
+    Ready,
+    /// Outbound queue counters, as JSON
+    Stats,
+    /// A file from the static directory, and whether only its headers were asked for
+    File(PathBuf, bool),
+    Error(StatusCode),
+}
+
+/// A stream that first replays bytes already read from it, so the WebSocket handshake can read
+/// the request it was routed by.
+pub struct Rewind<S> {
+    read: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+/// The rest of `path` if it is `base` or below it.
+pub fn within<'a>(base: &str, path: &'a str) -> Option<&'a str> {
+    let rest = path.strip_prefix(base.trim_end_matches('/'))?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Reads until the end of a request's head, or until it is too long. Anything the client sent
+/// after the head is included too.
+pub async fn read_head<S>(s: &mut S) -> Result<Vec<u8>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
+    while head.len() <= MAX_HEAD {
+        let n = s.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        // The end of the head may be split across reads
+        let from = head.len().saturating_sub(3);
+        head.extend_from_slice(&buf[..n]);
+        if head[from..].windows(4).any(|end| end == b"\r\n\r\n") {
+            break;
+        }
+    }
+    Ok(head)
+}
+
+pub fn route(head: &[u8], config: &Config) -> Route {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) | Err(httparse::Error::TooManyHeaders) => {
+            return Route::Page(Page::Error(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE));
+        }
+        Err(_) => return Route::Page(Page::Error(StatusCode::BAD_REQUEST)),
+    }
+
+    let path = req.path.unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+    let upgrade = req.headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("upgrade")
+            && header.value.eq_ignore_ascii_case(b"websocket")
+    });
+    let method = req.method.unwrap_or("");
+
+    let page = match path {
+        "/healthz" => Page::Health,
+        "/readyz" => Page::Ready,
+        "/stats" => Page::Stats,
+        _ if upgrade && within(&config.signalling_path, path).is_some() => return Route::Upgrade,
+        _ => match (&config.static_dir, within(&config.static_path, path)) {
+            (Some(dir), Some(file)) if method == "GET" || method == "HEAD" => {
+                match static_file(dir, file) {
+                    Some(file) => Page::File(file, method == "HEAD"),
+                    None => Page::Error(StatusCode::NOT_FOUND),
+                }
+            }
+            (Some(_), Some(_)) => Page::Error(StatusCode::METHOD_NOT_ALLOWED),
+            _ => Page::Error(StatusCode::NOT_FOUND),
+        },
+    };
+    Route::Page(page)
+}
+
+/// The address of the client a request is from. Behind trusted proxies, this is the last
+/// address in `X-Forwarded-For` not added by one of them, as anything before it may have been
+/// made up by the client.
+pub fn client_addr(head: &[u8], peer: IpAddr, config: &Config) -> IpAddr {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    if !config.trusted_proxies.contains(&peer) || req.parse(head).is_err() {
+        return peer;
+    }
+    // Proxies may each add a header of their own rather than append to the last
+    let hops = req
+        .headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("x-forwarded-for"))
+        .filter_map(|header| std::str::from_utf8(header.value).ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+
+    let mut addr = peer;
+    for hop in hops.into_iter().rev() {
+        if !config.trusted_proxies.contains(&addr) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(hop) => addr = hop,
+            Err(_) => break,
+        }
+    }
+    addr
+}
+
+/// Finds the file a request path refers to, never outside `dir`. Paths that match no file get
+/// `index.html`, as the client does its own routing.
+fn static_file(dir: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = dir.to_owned();
+    for part in path.split('/').filter(|part| !part.is_empty()) {
+        if part == "." || part == ".." || part.contains('\\') {
+            return None;
+        }
+        file.push(part);
+    }
+    if file.is_dir() {
+        file.push("index.html");
+    }
+    if !file.is_file() {
+        file = dir.join("index.html");
+    }
+    Some(file).filter(|file| file.is_file())
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Answers a request that is not a WebSocket upgrade, then closes the connection.
+pub async fn respond<S>(mut s: S, page: Page, rooms: &Rooms) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    let text = |status: StatusCode, text: &str| (status, "text/plain", text.as_bytes().to_vec());
+    let ((status, content_type, body), head_only) = match page {
+        Page::Health => (text(StatusCode::OK, "ok\n"), false),
+        Page::Ready if rooms.ready() => (text(StatusCode::OK, "ready\n"), false),
+        Page::Ready => (text(StatusCode::SERVICE_UNAVAILABLE, "not ready\n"), false),
+        Page::Stats => {
+            let stats = rooms.stats();
+            let json = serde_json::json!({
+                "queued": stats.queued.load(Ordering::Relaxed),
+                "coalesced": stats.coalesced.load(Ordering::Relaxed),
+                "evicted": stats.evicted.load(Ordering::Relaxed),
+            });
+            let body = serde_json::to_vec(&json)?;
+            ((StatusCode::OK, "application/json", body), false)
+        }
+        Page::File(path, head_only) => match tokio::fs::read(&path).await {
+            Ok(body) => ((StatusCode::OK, content_type(&path), body), head_only),
+            Err(e) => {
+                eprintln!("Could not read {:?}: {}", path, e);
+                (
+                    text(StatusCode::INTERNAL_SERVER_ERROR, "Internal error\n"),
+                    false,
+                )
+            }
+        },
+        Page::Error(status) => (text(status, &format!("{}\n", status)), false),
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or(""),
+        content_type,
+        body.len(),
+    )
+    .into_bytes();
+    if !head_only {
+        response.extend(body);
+    }
+    s.write_all(&response).await?;
+    s.shutdown().await?;
+    Ok(())
+}
+
+impl<S> Rewind<S> {
+    pub fn new(read: Vec<u8>, inner: S) -> Self {
+        Rewind {
+            read,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let rewind = &mut *self;
+        if rewind.pos < rewind.read.len() {
+            let n = buf.remaining().min(rewind.read.len() - rewind.pos);
+            buf.put_slice(&rewind.read[rewind.pos..rewind.pos + n]);
+            rewind.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut rewind.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(forwarded: &[&str], peer: &str) -> IpAddr {
+        let config = Config {
+            trusted_proxies: vec!["127.0.0.1".parse().unwrap(), "10.0.0.1".parse().unwrap()],
+            ..Config::default()
+        };
+        let mut head = "GET / HTTP/1.1\r\nHost: example.com\r\n".to_owned();
+        for value in forwarded {
+            head.push_str(&format!("X-Forwarded-For: {}\r\n", value));
+        }
+        head.push_str("\r\n");
+        client_addr(head.as_bytes(), peer.parse().unwrap(), &config)
+    }
+
+    #[test]
+    fn forwarded() {
+        let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        assert_eq!(client(&["1.2.3.4"], "127.0.0.1"), addr("1.2.3.4"));
+        // Only trusted proxies are listened to
+        assert_eq!(client(&["1.2.3.4"], "5.6.7.8"), addr("5.6.7.8"));
+        // Addresses before the first untrusted one may have been made up
+        assert_eq!(client(&["6.6.6.6, 1.2.3.4"], "127.0.0.1"), addr("1.2.3.4"));
+        assert_eq!(
+            client(&["6.6.6.6, 1.2.3.4", "10.0.0.1"], "127.0.0.1"),
+            addr("1.2.3.4")
+        );
+        assert_eq!(
+            client(&["1.2.3.4, nonsense"], "127.0.0.1"),
+            addr("127.0.0.1")
+        );
+        assert_eq!(client(&[], "127.0.0.1"), addr("127.0.0.1"));
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, HashSet};
+
+use super::message::{PeerId, Pos};
+
+/// Which pairs of peers are in range of each other, and so should keep a peer connection open.
+///
+/// A pair comes into range once the peers are within the larger of their subscription radii,
+/// and only goes out of range once they are `hysteresis` further apart than that, so peers
+/// moving around the edge do not repeatedly connect and disconnect. Peers in a private zone are
+/// in range of everyone else in the zone, and no one outside it.
+#[derive(Debug, Default)]
+pub struct Interest {
+    hysteresis: f32,
+    /// The peers each peer is in range of, recorded on both sides of a pair
+    pairs: HashMap<PeerId, HashSet<PeerId>>,
+}
+
+/// Where a peer is and how far it wants to hear, for checking whether a pair is in range.
+#[derive(Debug, Clone, Copy)]
+pub struct Subscriber {
+    pub id: PeerId,
+    pub pos: Pos,
+    pub radius: f32,
+    /// Always in range, as legacy clients connect to everyone and everyone connects to the peer
+    /// on stage
+    pub pinned: bool,
+    /// The private zone the peer is in, if any
+    pub private: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Enter(PeerId, PeerId),
+    Leave(PeerId, PeerId),
+}
+
+fn distance(a: Pos, b: Pos) -> f32 {
+    (a.x - b.x).hypot(a.y - b.y)
+}
+
+impl Interest {
+    pub fn new(hysteresis: f32) -> Self {
+        Interest {
+            hysteresis,
+            pairs: HashMap::new(),
+        }
+    }
+
+    /// The peers currently in range of `id`.
+    pub fn neighbours(&self, id: PeerId) -> impl Iterator<Item = PeerId> + '_ {
+        self.pairs.get(&id).into_iter().flatten().copied()
+    }
+
+    fn contains(&self, a: PeerId, b: PeerId) -> bool {
+        self.pairs.get(&a).map_or(false, |peers| peers.contains(&b))
+    }
+
+    fn link(&mut self, a: PeerId, b: PeerId) {
+        self.pairs.entry(a).or_default().insert(b);
+        self.pairs.entry(b).or_default().insert(a);
+    }
+
+    fn unlink(&mut self, a: PeerId, b: PeerId) {
+        self.detach(a, b);
+        self.detach(b, a);
+    }
+
+    fn detach(&mut self, a: PeerId, b: PeerId) {
+        if let Some(peers) = self.pairs.get_mut(&a) {
+            peers.remove(&b);
+            if peers.is_empty() {
+                self.pairs.remove(&a);
+            }
+        }
+    }
+
+    /// Rechecks the pairs between `peer` and each of `others`, returning those that changed.
+    pub fn update<I>(&mut self, peer: Subscriber, others: I) -> Vec<Change>
+    where
+        I: IntoIterator<Item = Subscriber>,
+    {
+        let mut changes = Vec::new();
+        for other in others.into_iter().filter(|other| other.id != peer.id) {
+            let reach = peer.radius.max(other.radius);
+            let dist = distance(peer.pos, other.pos);
+            let pinned = peer.pinned || other.pinned;
+            let private = peer.private.is_some() || other.private.is_some();
+            let (near, far) = if private {
+                let together = peer.private == other.private;
+                (together, !together)
+            } else {
+                (dist <= reach, dist > reach + self.hysteresis)
+            };
+
+            if self.contains(peer.id, other.id) {
+                if !pinned && far {
+                    self.unlink(peer.id, other.id);
+                    changes.push(Change::Leave(peer.id, other.id));
+                }
+            } else if pinned || near {
+                self.link(peer.id, other.id);
+                changes.push(Change::Enter(peer.id, other.id));
+            }
+        }
+        changes
+    }
+
+    /// Forgets a peer that has left the room.
+    pub fn remove(&mut self, id: PeerId) {
+        for other in self.pairs.remove(&id).unwrap_or_default() {
+            self.detach(other, id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(id: PeerId, x: f32) -> Subscriber {
+        Subscriber {
+            id,
+            pos: Pos { x, y: 0.0 },
+            radius: 10.0,
+            pinned: false,
+            private: None,
+        }
+    }
+
+    /// Moves `b` to each distance from `a` in turn, checking whether they are in range after.
+    fn check(interest: &mut Interest, a: Subscriber, b: Subscriber, steps: &[(f32, bool)]) {
+        for &(x, expected) in steps {
+            let b = Subscriber {
+                pos: Pos { x, y: 0.0 },
+                ..b
+            };
+            interest.update(a, vec![b]);
+            assert_eq!(interest.contains(a.id, b.id), expected, "at {}", x);
+            assert_eq!(interest.contains(b.id, a.id), expected, "at {}", x);
+        }
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut interest = Interest::new(5.0);
+        let b = Subscriber {
+            radius: 5.0,
+            ..at(2, 0.0)
+        };
+        // The larger radius is the reach, and pairs only leave beyond it plus the hysteresis
+        let steps = [
+            (12.0, false),
+            (10.0, true),
+            (14.0, true),
+            (15.0, true),
+            (15.5, false),
+            (12.0, false),
+            (9.0, true),
+        ];
+        check(&mut interest, at(1, 0.0), b, &steps);
+    }
+
+    #[test]
+    fn changes() {
+        let mut interest = Interest::new(5.0);
+        let a = at(1, 0.0);
+        assert_eq!(
+            interest.update(a, vec![at(2, 5.0), at(3, 50.0), a]),
+            [Change::Enter(1, 2)]
+        );
+        assert_eq!(interest.update(a, vec![at(2, 5.0)]), []);
+        assert_eq!(interest.update(a, vec![at(2, 50.0)]), [Change::Leave(1, 2)]);
+
+        interest.update(a, vec![at(2, 5.0), at(3, 5.0)]);
+        interest.remove(1);
+        assert_eq!(interest.neighbours(2).count(), 0);
+        assert_eq!(interest.neighbours(3).count(), 0);
+    }
+
+    #[test]
+    fn pinned() {
+        let mut interest = Interest::new(5.0);
+        let b = Subscriber {
+            pinned: true,
+            ..at(2, 0.0)
+        };
+        check(
+            &mut interest,
+            at(1, 0.0),
+            b,
+            &[(100.0, true), (1000.0, true)],
+        );
+        // Once unpinned, the pair is only kept while in range
+        check(
+            &mut interest,
+            at(1, 0.0),
+            at(2, 0.0),
+            &[(14.0, true), (100.0, false)],
+        );
+    }
+
+    #[test]
+    fn private() {
+        let mut interest = Interest::new(5.0);
+        let a = Subscriber {
+            private: Some(0),
+            ..at(1, 0.0)
+        };
+        let zone = |zone| Subscriber {
+            private: zone,
+            ..at(2, 0.0)
+        };
+        // Zones override distance both ways
+        check(&mut interest, a, zone(Some(0)), &[(100.0, true)]);
+        check(&mut interest, a, zone(Some(1)), &[(1.0, false)]);
+        check(&mut interest, a, zone(None), &[(1.0, false)]);
+        check(
+            &mut interest,
+            at(1, 0.0),
+            zone(None),
+            &[(1.0, true), (14.0, true)],
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+use super::message::{ClientMessage, Kind, Presence};
+use super::Config;
+
+/// Token bucket rate limit. Up to `burst` actions may happen at once, after which one more is
+/// allowed each `interval`.
+#[derive(Debug)]
+pub struct RateLimit {
+    burst: u32,
+    interval: Duration,
+    tokens: u32,
+    refilled: Instant,
+}
+
+/// Rate limits on each kind of message a peer sends.
+#[derive(Debug)]
+pub struct MessageLimits {
+    relay: RateLimit,
+    movement: RateLimit,
+    control: RateLimit,
+    /// Speaking toggles allowed on top of other control messages
+    speaking: RateLimit,
+    /// The presence last sent, to tell changes to only the speaking flag apart
+    presence: Option<Presence>,
+}
+
+/// Largest message of a kind peers may send, in bytes.
+pub fn max_size(kind: Kind, config: &Config) -> usize {
+    match kind {
+        Kind::Relay => config.max_relay_size,
+        Kind::Movement => config.max_movement_size,
+        Kind::Control => config.max_control_size,
+    }
+}
+
+impl MessageLimits {
+    pub fn new(config: &Config) -> Self {
+        MessageLimits {
+            relay: RateLimit::new(config.relay_burst, config.relay_interval),
+            movement: RateLimit::new(config.movement_burst, config.movement_interval),
+            control: RateLimit::new(config.control_burst, config.control_interval),
+            speaking: RateLimit::new(config.speaking_burst, config.speaking_interval),
+            presence: None,
+        }
+    }
+
+    /// Uses up a token for `message`, returning whether it is allowed.
+    pub fn take(&mut self, message: &ClientMessage) -> bool {
+        // Speaking toggles come in bursts while talking, so have an allowance of their own, and
+        // only count as control messages once it is used up
+        if let ClientMessage::Presence { presence } = message {
+            let old = self.presence.replace(*presence);
+            if old.map_or(false, |old| presence.only_speaking_changed(&old)) && self.speaking.take()
+            {
+                return true;
+            }
+        }
+        match message.kind() {
+            Kind::Relay => self.relay.take(),
+            Kind::Movement => self.movement.take(),
+            Kind::Control => self.control.take(),
+        }
+    }
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, interval: Duration) -> Self {
+        RateLimit {
+            burst,
+            interval,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Whether all tokens have been earned back, so the limit is as if new.
+    pub fn full(&self) -> bool {
+        let earned = self.refilled.elapsed().as_nanos() / self.interval.as_nanos().max(1);
+        earned >= u128::from(self.burst - self.tokens)
+    }
+
+    /// Uses up a token if there is one, returning whether the action is allowed.
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_nanos();
+        let earned = elapsed / self.interval.as_nanos().max(1);
+        if earned >= u128::from(self.burst - self.tokens) {
+            self.tokens = self.burst;
+            self.refilled = now;
+        } else {
+            // Only `earned` whole intervals are used up, keeping time towards the next token
+            self.tokens += earned as u32;
+            self.refilled += self.interval * earned as u32;
+        }
+
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presence(speaking: bool) -> ClientMessage {
+        let presence = Presence {
+            speaking,
+            ..Presence::default()
+        };
+        ClientMessage::Presence { presence }
+    }
+
+    #[test]
+    fn speaking() {
+        let config = Config {
+            control_burst: 2,
+            control_interval: Duration::from_secs(60),
+            speaking_burst: 3,
+            speaking_interval: Duration::from_secs(60),
+            ..Config::default()
+        };
+        let mut limits = MessageLimits::new(&config);
+        // The first presence is a change of everything, as far as the limits know
+        assert!(limits.take(&presence(false)));
+        // Toggles use up their own allowance, then what is left for control messages
+        let allowed = (0..10)
+            .filter(|&i| limits.take(&presence(i % 2 == 0)))
+            .count();
+        assert_eq!(allowed, 4);
+        assert!(!limits.take(&ClientMessage::RaiseHand));
+    }
+}
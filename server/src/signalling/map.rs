@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::message::Pos;
+use super::Error;
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Rect {
+    pub fn contains(&self, pos: Pos) -> bool {
+        pos.x >= self.x
+            && pos.x <= self.x + self.width
+            && pos.y >= self.y
+            && pos.y <= self.y + self.height
+    }
+
+    /// How far along the line from `from` to `to` it first enters the rectangle, as a fraction
+    /// of the line's length.
+    fn entry(&self, from: Pos, to: Pos) -> Option<f32> {
+        let (mut enter, mut exit) = (0.0f32, 1.0f32);
+        let axes = [
+            (from.x, to.x - from.x, self.x, self.x + self.width),
+            (from.y, to.y - from.y, self.y, self.y + self.height),
+        ];
+        for &(start, delta, min, max) in &axes {
+            if delta == 0.0 {
+                if start < min || start > max {
+                    return None;
+                }
+                continue;
+            }
+            let (a, b) = ((min - start) / delta, (max - start) / delta);
+            enter = enter.max(a.min(b));
+            exit = exit.min(a.max(b));
+        }
+        if enter <= exit {
+            Some(enter)
+        } else {
+            None
+        }
+    }
+}
+
+/// A named area of a room. Peers inside a private zone are only in range of each other.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Zone {
+    pub name: String,
+    pub area: Rect,
+    #[serde(default)]
+    pub private: bool,
+}
+
+/// Layout of a room, loaded from JSON.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Map {
+    /// The far corner of the room, peers are kept between it and the origin
+    pub bounds: Pos,
+    /// Areas peers cannot move into or through
+    #[serde(default)]
+    pub walls: Vec<Rect>,
+    /// Where peers may appear when joining, anywhere outside a wall if empty
+    #[serde(default)]
+    pub spawns: Vec<Pos>,
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+}
+
+impl Default for Map {
+    fn default() -> Self {
+        Map {
+            bounds: Pos { x: 800.0, y: 600.0 },
+            walls: Vec::new(),
+            spawns: Vec::new(),
+            zones: Vec::new(),
+        }
+    }
+}
+
+impl Map {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let map = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&map)?)
+    }
+
+    fn in_wall(&self, pos: Pos) -> bool {
+        self.walls.iter().any(|wall| wall.contains(pos))
+    }
+
+    /// Picks where a new peer appears.
+    pub fn spawn(&self) -> Pos {
+        if !self.spawns.is_empty() {
+            return self.spawns[rand::random::<usize>() % self.spawns.len()];
+        }
+        let random = || Pos {
+            x: rand::random::<f32>() * self.bounds.x,
+            y: rand::random::<f32>() * self.bounds.y,
+        };
+        // Gives up on avoiding walls if the room is almost entirely wall
+        (0..100)
+            .map(|_| random())
+            .find(|&pos| !self.in_wall(pos))
+            .unwrap_or_else(random)
+    }
+
+    /// Keeps a peer moving from `from` to `to` inside the room, stopping it short of any wall in
+    /// the way.
+    pub fn constrain(&self, from: Pos, to: Pos) -> Pos {
+        let to = Pos {
+            x: to.x.max(0.0).min(self.bounds.x),
+            y: to.y.max(0.0).min(self.bounds.y),
+        };
+        // Peers already inside a wall, e.g. from a badly placed spawn, may leave it
+        let hit = self
+            .walls
+            .iter()
+            .filter(|wall| !wall.contains(from))
+            .filter_map(|wall| wall.entry(from, to))
+            .fold(None, |first: Option<f32>, t| {
+                Some(first.map_or(t, |f| f.min(t)))
+            });
+        match hit {
+            Some(t) => {
+                // Stop just outside the wall, rather than on its edge
+                let t = (t - 0.01 / (to.x - from.x).hypot(to.y - from.y)).max(0.0);
+                Pos {
+                    x: from.x + (to.x - from.x) * t,
+                    y: from.y + (to.y - from.y) * t,
+                }
+            }
+            None => to,
+        }
+    }
+
+    /// The zone a position is in, if any. Zones listed first take precedence where they overlap.
+    pub fn zone_at(&self, pos: Pos) -> Option<usize> {
+        self.zones.iter().position(|zone| zone.area.contains(pos))
+    }
+}
@@ -1,15 +1,24 @@
 use serde::{Deserialize, Serialize};
 
+use super::auth::Role;
+use super::protocol::Protocol;
+
+/// Identifies a peer for as long as it stays in its room, including across reconnects.
+pub type PeerId = u64;
+
+/// Identifies a conversation bubble within its room.
+pub type BubbleId = u64;
+
 // TODO: use RawValue for efficiency on pass-through data
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PeerMessage {
-    pub peer: usize,
+    pub peer: PeerId,
     #[serde(flatten)]
     pub data: PeerMessageData,
 }
 
 impl PeerMessage {
-    pub fn forward(self, source: usize) -> ServerMessage {
+    pub fn forward(self, source: PeerId) -> ServerMessage {
         ServerMessage::PeerMessage {
             message: PeerMessage {
                 peer: source,
@@ -29,18 +38,329 @@ pub enum PeerMessageData {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
-    Hello { state: Peer, peers: Vec<Peer> },
-    AddPeer { peer: Peer },
-    RemovePeer { peer: usize },
-    MovePeer { peer: usize, pos: Pos },
-    PeerMessage { message: PeerMessage },
+    Hello {
+        state: Peer,
+        peers: Vec<Peer>,
+        /// Secret for resuming the session if the connection is lost
+        resume: String,
+        /// The protocol version picked for the client, and the features it supports
+        protocol: Protocol,
+        /// The unit positions are given in by `Positions`
+        quantum: f32,
+    },
+    AddPeer {
+        peer: Peer,
+    },
+    RemovePeer {
+        peer: PeerId,
+    },
+    MovePeer {
+        peer: PeerId,
+        pos: Pos,
+    },
+    /// A peer's presence changed
+    Presence {
+        peer: PeerId,
+        #[serde(flatten)]
+        presence: Presence,
+    },
+    /// A peer changed its profile
+    PeerUpdated {
+        peer: PeerId,
+        #[serde(flatten)]
+        profile: Profile,
+    },
+    PeerMessage {
+        message: PeerMessage,
+    },
+    /// Peers nearby that moved since the last tick, as `[peer, x, y]` in multiples of the quantum
+    /// from `Hello`
+    Positions {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        positions: Vec<(PeerId, i32, i32)>,
+        /// Changes since the last position sent for each peer, if the server sends deltas
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        deltas: Vec<(PeerId, i32, i32)>,
+        /// The zone each peer that entered or left one is now in
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        zones: Vec<(PeerId, Option<String>)>,
+    },
+    /// A chat message, sent to its sender as well as the peers it was addressed to
+    Chat {
+        from: PeerId,
+        scope: ChatScope,
+        text: String,
+        /// When the server received the message, in milliseconds since the Unix epoch
+        time: u64,
+        /// Where a room-wide message is in the room's history
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+    },
+    /// Room-wide chat messages, oldest first. Sent after `Hello` with the latest messages, and in
+    /// reply to `History` with older ones.
+    History {
+        messages: Vec<HistoryEntry>,
+        /// Whether there are older messages to ask for
+        more: bool,
+    },
+    /// Who has the stage, and should be heard at full volume wherever they are. Sent after
+    /// `Hello`, and whenever the stage or the queue for it changes.
+    Stage {
+        peer: Option<PeerId>,
+        /// Peers with their hand raised, in the order they raised it
+        hands: Vec<PeerId>,
+    },
+    /// The members of a conversation bubble changed, it has dissolved if there are none left
+    BubbleChanged {
+        bubble: BubbleId,
+        members: Vec<PeerId>,
+    },
+    /// A host asks the peer to mute its microphone
+    MuteRequested {
+        by: PeerId,
+    },
+    /// A peer has come within range, and a peer connection should be opened to it. The polite
+    /// side waits for the other to make an offer.
+    InRange {
+        peer: PeerId,
+        polite: bool,
+        /// One side is a spectator, and only media from the other is sent. Spectators are not
+        /// listed in `Hello` or `AddPeer`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        receive_only: bool,
+    },
+    /// A peer is no longer in range, and the peer connection to it should be closed
+    OutOfRange {
+        peer: PeerId,
+    },
+    /// A client message was rejected, the session continues unless there are too many of these
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// The type of the rejected message, if it could be read
+        in_reply_to: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The message was not valid JSON
+    InvalidJson,
+    /// The message type is not one the server knows
+    UnknownType,
+    /// The message is missing fields, or they have the wrong type
+    InvalidMessage,
+    /// The message was addressed to a peer that is not in the room
+    UnknownPeer,
+    /// The client is sending messages of this type too quickly
+    RateLimited,
+    /// The sender's role does not allow it to send the message, or not to that peer
+    Forbidden,
+    /// The message is larger than allowed for its type
+    TooLarge,
+}
+
+impl ErrorCode {
+    /// Whether the client sent something it never should have, rather than losing a race with
+    /// the room or running into a limit.
+    pub fn is_malformed(self) -> bool {
+        match self {
+            ErrorCode::InvalidJson
+            | ErrorCode::UnknownType
+            | ErrorCode::InvalidMessage
+            | ErrorCode::TooLarge => true,
+            ErrorCode::UnknownPeer | ErrorCode::RateLimited | ErrorCode::Forbidden => false,
+        }
+    }
+}
+
+/// Why a client message was rejected, to be reported back to the client.
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub in_reply_to: Option<String>,
+}
+
+impl From<ProtocolError> for ServerMessage {
+    fn from(e: ProtocolError) -> Self {
+        ServerMessage::Error {
+            code: e.code,
+            message: e.message,
+            in_reply_to: e.in_reply_to,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
-    Peer { message: PeerMessage },
-    Move { pos: Pos },
+    Peer {
+        message: PeerMessage,
+    },
+    Move {
+        pos: Pos,
+    },
+    /// Tells the other peers about the peer's microphone, camera and whereabouts
+    Presence {
+        #[serde(flatten)]
+        presence: Presence,
+    },
+    /// Replaces the peer's profile
+    SetProfile {
+        #[serde(flatten)]
+        profile: Profile,
+    },
+    /// Sets how far away peers come into range
+    SetRadius {
+        radius: f32,
+    },
+    Chat {
+        scope: ChatScope,
+        text: String,
+    },
+    /// Removes a peer from the room, for hosts only
+    Kick {
+        peer: PeerId,
+    },
+    /// Asks a peer to mute its microphone, for hosts only
+    RequestMute {
+        peer: PeerId,
+    },
+    /// Removes a peer from the room, and keeps its user out for as long as the room is open. Its
+    /// address is banned too if `ip` is set, or if it did not join with a token, unless the
+    /// address is a trusted proxy's or this machine's. For hosts only.
+    Ban {
+        peer: PeerId,
+        #[serde(default)]
+        ip: bool,
+    },
+    /// Moves a peer, for hosts only
+    Teleport {
+        peer: PeerId,
+        pos: Pos,
+    },
+    /// Gives the stage to a peer, or takes it away. Hosts may give it to anyone, and the peer
+    /// on stage may hand it to someone with their hand raised or step down.
+    SetStage {
+        peer: Option<PeerId>,
+    },
+    /// Joins the queue for the stage
+    RaiseHand,
+    LowerHand,
+    /// Opens a receive-only connection to a peer that allows spectators, for spectators only
+    Watch {
+        peer: PeerId,
+    },
+    Unwatch {
+        peer: PeerId,
+    },
+    /// Asks for room-wide chat messages from before the one with ID `before`, or the latest if
+    /// it is not given
+    History {
+        before: Option<u64>,
+        limit: Option<usize>,
+    },
+    /// Any message type the server does not know
+    #[serde(other)]
+    Unknown,
+}
+
+/// What a client message is for. Each kind has its own size limit, and its own rate limit for
+/// each peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// SDP and ICE candidates relayed to another peer
+    Relay,
+    /// Moving around the map
+    Movement,
+    /// Everything else
+    Control,
+}
+
+impl ClientMessage {
+    pub fn kind(&self) -> Kind {
+        match self {
+            ClientMessage::Peer { .. } => Kind::Relay,
+            ClientMessage::Move { .. } | ClientMessage::SetRadius { .. } => Kind::Movement,
+            _ => Kind::Control,
+        }
+    }
+
+    /// The message's `type`, for reporting errors.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ClientMessage::Peer { .. } => "Peer",
+            ClientMessage::Move { .. } => "Move",
+            ClientMessage::Presence { .. } => "Presence",
+            ClientMessage::SetProfile { .. } => "SetProfile",
+            ClientMessage::SetRadius { .. } => "SetRadius",
+            ClientMessage::Chat { .. } => "Chat",
+            ClientMessage::Kick { .. } => "Kick",
+            ClientMessage::RequestMute { .. } => "RequestMute",
+            ClientMessage::Ban { .. } => "Ban",
+            ClientMessage::Teleport { .. } => "Teleport",
+            ClientMessage::SetStage { .. } => "SetStage",
+            ClientMessage::RaiseHand => "RaiseHand",
+            ClientMessage::LowerHand => "LowerHand",
+            ClientMessage::Watch { .. } => "Watch",
+            ClientMessage::Unwatch { .. } => "Unwatch",
+            ClientMessage::History { .. } => "History",
+            ClientMessage::Unknown => "Unknown",
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Self, ProtocolError> {
+        let value =
+            serde_json::from_str::<serde_json::Value>(content).map_err(|e| ProtocolError {
+                code: ErrorCode::InvalidJson,
+                message: e.to_string(),
+                in_reply_to: None,
+            })?;
+        let ty = value
+            .get("type")
+            .and_then(|ty| ty.as_str())
+            .map(str::to_owned);
+
+        match serde_json::from_value::<ClientMessage>(value) {
+            Ok(ClientMessage::Unknown) => Err(ProtocolError {
+                code: ErrorCode::UnknownType,
+                message: "Unknown message type".to_owned(),
+                in_reply_to: ty,
+            }),
+            Ok(msg) => Ok(msg),
+            Err(e) => Err(ProtocolError {
+                code: ErrorCode::InvalidMessage,
+                message: e.to_string(),
+                in_reply_to: ty,
+            }),
+        }
+    }
+}
+
+/// Who a chat message is for.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(tag = "type")]
+pub enum ChatScope {
+    Peer {
+        peer: PeerId,
+    },
+    /// Everyone within `radius` of the sender
+    Nearby {
+        radius: f32,
+    },
+    /// Everyone in the sender's conversation bubble
+    Bubble,
+    Room,
+}
+
+/// A room-wide chat message, as kept in the room's history.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub from: PeerId,
+    pub text: String,
+    pub time: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Copy, Clone)]
@@ -49,8 +369,65 @@ pub struct Pos {
     pub y: f32,
 }
 
+impl Pos {
+    /// Rounds to the nearest multiple of `quantum`, returning the number of multiples.
+    pub fn quantize(self, quantum: f32) -> (i32, i32) {
+        (
+            (self.x / quantum).round() as i32,
+            (self.y / quantum).round() as i32,
+        )
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Peer {
-    pub id: usize,
+    pub id: PeerId,
     pub pos: Pos,
+    /// The name of the map zone the peer is in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    #[serde(flatten)]
+    pub profile: Profile,
+    pub presence: Presence,
+    pub role: Role,
+    /// Spectators are only sent their own record, as they are not on the map
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub spectator: bool,
+}
+
+/// What a peer is up to, beyond what can be seen from its position.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Presence {
+    pub muted: bool,
+    pub camera_off: bool,
+    pub away: bool,
+    pub speaking: bool,
+    /// Whether spectators may watch the peer
+    pub allow_spectators: bool,
+}
+
+impl Presence {
+    /// Whether this differs from `old` in whether the peer is speaking, and in nothing else.
+    pub fn only_speaking_changed(&self, old: &Presence) -> bool {
+        self.speaking != old.speaking
+            && Presence {
+                speaking: old.speaking,
+                ..*self
+            } == *old
+    }
+}
+
+/// How a peer presents itself to the others.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Profile {
+    /// Display name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// URL of a picture, or a hash to look one up by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    /// Anything else clients want to share about their peer
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
 }
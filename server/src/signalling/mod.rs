@@ -1,145 +1,226 @@
+mod admission;
+pub mod auth;
+mod bubble;
+mod config;
 mod error;
+mod grid;
+mod handshake;
+mod history;
+mod http;
+mod interest;
+mod limit;
+mod map;
 pub mod message;
+mod outbox;
+mod profile;
+pub mod protocol;
+mod room;
+mod tls;
 
-use std::collections::HashMap;
+use std::io;
 use std::marker::Unpin;
-use std::sync::{Arc, Mutex};
+use std::net::IpAddr;
 
-use futures::{future, TryFutureExt};
-use futures::{Sink, SinkExt, Stream, StreamExt, TryStreamExt};
-use tokio::runtime;
+use admission::Admission;
+use futures::TryFutureExt;
+use futures::{StreamExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{runtime, time};
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::WebSocketStream;
 
+pub use config::{Config, TlsFiles};
 pub use error::Error;
-use message::{ClientMessage, Pos, ServerMessage};
-
-struct Peer<U> {
-    pos: Pos,
-    sink: U,
-}
-
-async fn handle_client<U, S>(
-    mut s: S,
-    id: usize,
-    peers: &Arc<Mutex<HashMap<usize, Peer<U>>>>,
+use handshake::Join;
+use http::{Page, Rewind, Route};
+pub use map::{Map, Rect, Zone};
+use message::{ClientMessage, ErrorCode, ProtocolError};
+use room::Rooms;
+use tls::Tls;
+
+async fn handle_client<S>(
+    s: WebSocketStream<S>,
+    conn: usize,
+    join: Join,
+    rooms: &Rooms,
+    config: &Config,
 ) -> Result<(), Error>
 where
-    U: Sink<tungstenite::Message> + Unpin,
-    Error: From<U::Error>,
-    S: Stream<Item = Result<tungstenite::Message, tungstenite::Error>> + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    let pos = peers
-        .lock()?
-        .get(&id)
-        .map(|peer| peer.pos)
-        .unwrap_or_default();
-
-    let msg = tungstenite::Message::Text(serde_json::to_string(&ServerMessage::Hello {
-        state: message::Peer { id, pos },
-        peers: peers
-            .lock()?
-            .iter()
-            .filter(|(&peer_id, _)| peer_id != id)
-            .map(|(id, peer)| message::Peer {
-                id: *id,
-                pos: peer.pos,
-            })
-            .collect(),
-    })?);
-
-    if let Some(peer) = peers.lock()?.get_mut(&id) {
-        peer.sink.send(msg).await?;
-    }
-
-    let msg = tungstenite::Message::Text(serde_json::to_string(&ServerMessage::AddPeer {
-        peer: message::Peer { id, pos },
-    })?);
-
-    future::join_all(
-        peers
-            .lock()?
-            .iter_mut()
-            .filter(|(&peer_id, _)| peer_id != id)
-            .map(|(_, peer)| peer.sink.send(msg.clone())),
-    )
-    .await;
+    let (sink, mut source) = s.split();
+    let (membership, rx) = rooms.join(conn, join)?;
+    // Closes the socket once the room drops the peer's outbox
+    tokio::spawn(rx.forward(sink, config.ping_interval));
+    // Checked here rather than by the room, so a flood is refused before it is queued for it
+    let mut limits = limit::MessageLimits::new(config);
+    // Clients that keep going regardless are disconnected, as they would be for malformed ones
+    let mut rate_limited =
+        limit::RateLimit::new(config.max_protocol_errors, config.protocol_error_interval);
+
+    loop {
+        let msg = match time::timeout(config.idle_timeout, source.next()).await {
+            Ok(Some(msg)) => msg?,
+            Ok(None) => break,
+            Err(_) => {
+                eprintln!("Client {} timed out", conn);
+                break;
+            }
+        };
 
-    while let Some(Ok(msg)) = s.next().await {
         match msg {
-            tungstenite::Message::Text(content) => {
-                match serde_json::from_str::<ClientMessage>(&content)? {
-                    ClientMessage::Peer { message: msg } => {
-                        if let Some(peer) = peers.lock()?.get_mut(&msg.peer) {
-                            let msg = tungstenite::Message::Text(serde_json::to_string(
-                                &msg.forward(id),
-                            )?);
-                            peer.sink.send(msg).await?;
-                        }
-                    }
-                    ClientMessage::Move { pos } => {
-                        let msg = tungstenite::Message::Text(serde_json::to_string(
-                            &ServerMessage::MovePeer { peer: id, pos },
-                        )?);
-                        future::join_all(
-                            peers
-                                .lock()?
-                                .iter_mut()
-                                .map(|(_, peer)| peer.sink.send(msg.clone())),
-                        )
-                        .await;
+            tungstenite::Message::Text(content) => match ClientMessage::parse(&content) {
+                Ok(msg) if content.len() > limit::max_size(msg.kind(), config) => membership
+                    .reject(ProtocolError {
+                        code: ErrorCode::TooLarge,
+                        message: format!(
+                            "{} messages are limited to {} bytes",
+                            msg.type_name(),
+                            limit::max_size(msg.kind(), config)
+                        ),
+                        in_reply_to: Some(msg.type_name().to_owned()),
+                    })?,
+                Ok(msg) if !limits.take(&msg) => {
+                    if !rate_limited.take() {
+                        membership.kick("Sending messages too quickly");
+                        break;
                     }
+                    membership.reject(ProtocolError {
+                        code: ErrorCode::RateLimited,
+                        message: format!("Sending {} messages too quickly", msg.type_name()),
+                        in_reply_to: Some(msg.type_name().to_owned()),
+                    })?
                 }
-            }
+                Ok(msg) => membership.send(msg)?,
+                Err(e) => membership.reject(e)?,
+            },
             tungstenite::Message::Close(_) => {
+                membership.leave();
                 break;
             }
-            _ => {}
+            // Pings are answered by tungstenite, but like pongs they show the client is alive
+            tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_) => {}
+            tungstenite::Message::Binary(_) => membership.reject(ProtocolError {
+                code: ErrorCode::InvalidMessage,
+                message: "Binary messages are not supported".to_owned(),
+                in_reply_to: None,
+            })?,
         }
     }
 
-    peers.lock().unwrap().remove(&id);
-    let msg = tungstenite::Message::Text(serde_json::to_string(&ServerMessage::RemovePeer {
-        peer: id,
-    })?);
-    future::join_all(
-        peers
-            .lock()?
-            .values_mut()
-            .map(|peer| peer.sink.send(msg.clone())),
-    )
-    .await;
-
     Ok(())
 }
 
-pub fn main(address: &str) -> Result<(), Error> {
+async fn serve<S>(
+    mut s: S,
+    conn: usize,
+    addr: IpAddr,
+    rooms: &Rooms,
+    config: &Config,
+    admission: &Admission,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Like idle clients, clients that send their request too slowly are given up on
+    let head = time::timeout(config.idle_timeout, http::read_head(&mut s))
+        .await
+        .map_err(io::Error::from)??;
+
+    // Clients behind a trusted proxy are only known once their request has been read
+    let client = http::client_addr(&head, addr, config);
+    let _ticket = if config.trusted_proxies.contains(&addr) {
+        match admission.admit(client) {
+            Ok(ticket) => Some(ticket),
+            Err(reason) => {
+                eprintln!("Refused connection from {}: {}", client, reason);
+                let page = Page::Error(StatusCode::TOO_MANY_REQUESTS);
+                return http::respond(s, page, rooms).await;
+            }
+        }
+    } else {
+        None
+    };
+
+    match http::route(&head, config) {
+        Route::Upgrade => {
+            let handshake = handshake::accept(Rewind::new(head, s), client, config);
+            let (join, ws) = time::timeout(config.idle_timeout, handshake)
+                .await
+                .map_err(io::Error::from)??;
+            handle_client(ws, conn, join, rooms, config).await
+        }
+        Route::Page(page) => http::respond(s, page, rooms).await,
+    }
+}
+
+pub fn main(config: Config) -> Result<(), Error> {
     let rt = runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
 
-    let clients = Arc::new(Mutex::new(HashMap::new()));
+    let address = config.address.clone();
+    let rooms = Rooms::new(config.clone());
+    let tls = config.tls.as_ref().map(Tls::load).transpose()?;
+    let admission = Admission::new(&config);
 
     let listener = tokio::net::TcpListener::bind(address)
         .map_ok(TcpListenerStream::new)
         .try_flatten_stream()
-        .err_into()
-        .and_then(|s| tokio_tungstenite::accept_async(s).err_into())
         .enumerate()
-        .map(|(id, s)| {
-            s.map(|s| {
-                let (sink, source) = s.split();
-                let pos = Pos {
-                    x: rand::random::<f32>() * 800.0,
-                    y: rand::random::<f32>() * 600.0,
+        .map(|(conn, s)| s.map(|s| (conn, s)));
+
+    let result = listener
+        .err_into()
+        .try_for_each_concurrent(None, |(conn, s)| {
+            let (rooms, config, tls, admission) = (&rooms, &config, &tls, &admission);
+            async move {
+                let addr = match s.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        eprintln!("Client {} disconnected: {:?}", conn, e);
+                        return Ok(());
+                    }
+                };
+                // Checked before anything is read, so refused clients cost as little as possible
+                let _ticket = if config.trusted_proxies.contains(&addr.ip()) {
+                    None
+                } else {
+                    match admission.admit(addr.ip()) {
+                        Ok(ticket) => Some(ticket),
+                        Err(reason) => {
+                            eprintln!("Refused connection from {}: {}", addr.ip(), reason);
+                            return Ok(());
+                        }
+                    }
                 };
 
-                clients.lock().unwrap().insert(id, Peer { pos, sink });
-                (id, source)
-            })
+                let result = match tls {
+                    Some(tls) => match tls.acceptor() {
+                        // Clients that never finish the handshake would otherwise hold on to
+                        // their connection forever
+                        Ok(acceptor) => {
+                            match time::timeout(config.idle_timeout, acceptor.accept(s)).await {
+                                Ok(Ok(s)) => {
+                                    serve(s, conn, addr.ip(), rooms, config, admission).await
+                                }
+                                Ok(Err(e)) => Err(e.into()),
+                                Err(elapsed) => Err(io::Error::from(elapsed).into()),
+                            }
+                        }
+                        Err(e) => Err(e),
+                    },
+                    None => serve(s, conn, addr.ip(), rooms, config, admission).await,
+                };
+                // A failing client should not take down the server
+                if let Err(e) = result {
+                    eprintln!("Client {} disconnected: {:?}", conn, e);
+                }
+                Ok(())
+            }
         });
 
-    let result = listener.try_for_each_concurrent(None, |(i, c)| handle_client(c, i, &clients));
-
     rt.block_on(result)
 }
@@ -0,0 +1,348 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use futures::{pin_mut, Sink, SinkExt};
+use tokio::sync::Notify;
+use tokio::time::{interval_at, Instant};
+use tokio_tungstenite::tungstenite;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+
+use super::message::PeerId;
+use super::Error;
+
+/// Counters for outbound queue events, shared by all rooms and served at `/stats`.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Messages queued for delivery
+    pub queued: AtomicU64,
+    /// Stale `MovePeer` updates replaced by a newer position before being sent
+    pub coalesced: AtomicU64,
+    /// Peers disconnected for letting their queue grow past the limit
+    pub evicted: AtomicU64,
+}
+
+enum Queued {
+    Move {
+        peer: PeerId,
+        msg: tungstenite::Message,
+    },
+    Positions(tungstenite::Message),
+    Other(tungstenite::Message),
+}
+
+impl Queued {
+    fn into_message(self) -> tungstenite::Message {
+        match self {
+            Queued::Move { msg, .. } | Queued::Positions(msg) | Queued::Other(msg) => msg,
+        }
+    }
+}
+
+struct Queue {
+    messages: VecDeque<Queued>,
+    closed: bool,
+    reason: Option<CloseFrame<'static>>,
+    coalesced: u64,
+    /// Whether a `Positions` message is waiting to be sent
+    positions: bool,
+}
+
+struct Shared {
+    id: usize,
+    queue: Mutex<Queue>,
+    notify: Notify,
+    limit: usize,
+    stats: Arc<Stats>,
+}
+
+impl Shared {
+    fn close(&self, reason: Option<CloseFrame<'static>>) {
+        if let Ok(mut queue) = self.queue.lock() {
+            if !queue.closed {
+                queue.closed = true;
+                queue.reason = reason;
+            }
+        }
+        self.notify.notify_one();
+    }
+}
+
+/// The room's end of a peer's outbound queue, closing the connection when dropped.
+pub struct Outbox(Arc<Shared>);
+
+/// The writer task's end of a peer's outbound queue.
+pub struct Receiver(Arc<Shared>);
+
+pub fn outbox(id: usize, limit: usize, stats: Arc<Stats>) -> (Outbox, Receiver) {
+    let shared = Arc::new(Shared {
+        id,
+        queue: Mutex::new(Queue {
+            messages: VecDeque::new(),
+            closed: false,
+            reason: None,
+            coalesced: 0,
+            positions: false,
+        }),
+        notify: Notify::new(),
+        limit,
+        stats,
+    });
+    (Outbox(shared.clone()), Receiver(shared))
+}
+
+impl Outbox {
+    /// Queues a message, never dropping it. The peer is disconnected instead if it has fallen
+    /// too far behind.
+    ///
+    /// Returns whether the peer is still connected, which it is not once it has fallen behind
+    /// or its writer has stopped.
+    pub fn send(&self, msg: tungstenite::Message) -> bool {
+        self.push(Queued::Other(msg))
+    }
+
+    /// Queues a position update for `peer`, replacing one that has not yet been sent.
+    pub fn send_move(&self, peer: PeerId, msg: tungstenite::Message) -> bool {
+        self.push(Queued::Move { peer, msg })
+    }
+
+    /// Queues a `Positions` message. Only one is queued at a time, see `positions_pending`.
+    pub fn send_positions(&self, msg: tungstenite::Message) -> bool {
+        self.push(Queued::Positions(msg))
+    }
+
+    /// Whether the last `Positions` message is still waiting to be sent, in which case the room
+    /// holds on to changes until it has been, and sends them together.
+    pub fn positions_pending(&self) -> bool {
+        // Once closed, sending fails instead, so the room finds out the peer is gone
+        match self.0.queue.lock() {
+            Ok(queue) => queue.positions && !queue.closed,
+            Err(_) => false,
+        }
+    }
+
+    fn push(&self, msg: Queued) -> bool {
+        let Shared {
+            id,
+            queue,
+            notify,
+            limit,
+            stats,
+        } = &*self.0;
+        let mut queue = match queue.lock() {
+            Ok(queue) => queue,
+            Err(_) => return false,
+        };
+        if queue.closed {
+            return false;
+        }
+
+        if let Queued::Move { peer, msg } = msg {
+            let stale = queue.messages.iter_mut().find_map(|queued| match queued {
+                Queued::Move { peer: p, msg } if *p == peer => Some(msg),
+                _ => None,
+            });
+            match stale {
+                Some(stale) => {
+                    *stale = msg;
+                    queue.coalesced += 1;
+                    stats.coalesced.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+                None => queue.messages.push_back(Queued::Move { peer, msg }),
+            }
+        } else {
+            queue.positions |= matches!(msg, Queued::Positions(_));
+            queue.messages.push_back(msg);
+        }
+        stats.queued.fetch_add(1, Ordering::Relaxed);
+
+        if queue.messages.len() > *limit {
+            eprintln!(
+                "Disconnecting slow peer {}: {} messages queued, {} moves coalesced",
+                id,
+                queue.messages.len(),
+                queue.coalesced,
+            );
+            stats.evicted.fetch_add(1, Ordering::Relaxed);
+            // The backlog is discarded so the close frame goes out immediately
+            queue.messages.clear();
+            queue.closed = true;
+            queue.reason = Some(CloseFrame {
+                code: CloseCode::Again,
+                reason: "Too slow to keep up with room".into(),
+            });
+        }
+        notify.notify_one();
+        !queue.closed
+    }
+
+    /// Disconnects the peer once the messages already queued have been sent.
+    pub fn close(&self, reason: CloseFrame<'static>) {
+        self.0.close(Some(reason));
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.0.close(None);
+    }
+}
+
+impl Receiver {
+    async fn next(&self) -> Option<tungstenite::Message> {
+        loop {
+            {
+                let mut queue = self.0.queue.lock().ok()?;
+                if let Some(msg) = queue.messages.pop_front() {
+                    if let Queued::Positions(_) = msg {
+                        queue.positions = false;
+                    }
+                    return Some(msg.into_message());
+                } else if queue.closed {
+                    return None;
+                }
+            }
+            self.0.notify.notified().await;
+        }
+    }
+
+    /// Writes queued messages to `sink` until the outbox is closed, then closes the socket.
+    ///
+    /// The peer is pinged every `ping_interval`, so its reader can tell if the connection is
+    /// lost.
+    pub async fn forward<S>(self, mut sink: S, ping_interval: Duration) -> Result<(), Error>
+    where
+        S: Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+    {
+        let mut ping = interval_at(Instant::now() + ping_interval, ping_interval);
+        loop {
+            let next = self.next();
+            let tick = ping.tick();
+            pin_mut!(next, tick);
+            match future::select(next, tick).await {
+                Either::Left((Some(msg), _)) => sink.send(msg).await?,
+                Either::Left((None, _)) => break,
+                Either::Right(_) => sink.send(tungstenite::Message::Ping(Vec::new())).await?,
+            }
+        }
+
+        let reason = self.0.queue.lock()?.reason.take();
+        if reason.is_some() {
+            sink.send(tungstenite::Message::Close(reason)).await?;
+        }
+        sink.close().await?;
+        Ok(())
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.0.close(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime;
+
+    use super::*;
+
+    fn text(text: &str) -> tungstenite::Message {
+        tungstenite::Message::Text(text.to_owned())
+    }
+
+    /// The messages waiting to be sent, oldest first.
+    fn queued(rx: &Receiver) -> Vec<String> {
+        let queue = rx.0.queue.lock().unwrap();
+        let text = |queued: &Queued| match queued {
+            Queued::Move { msg, .. } | Queued::Positions(msg) | Queued::Other(msg) => {
+                msg.to_text().unwrap().to_owned()
+            }
+        };
+        queue.messages.iter().map(text).collect()
+    }
+
+    fn counts(stats: &Stats) -> (u64, u64, u64) {
+        (
+            stats.queued.load(Ordering::Relaxed),
+            stats.coalesced.load(Ordering::Relaxed),
+            stats.evicted.load(Ordering::Relaxed),
+        )
+    }
+
+    #[test]
+    fn move_replaced() {
+        let stats = Arc::new(Stats::default());
+        let (outbox, rx) = outbox(0, 10, stats.clone());
+        assert!(outbox.send_move(1, text("1 at a")));
+        assert!(outbox.send(text("offer")));
+        assert!(outbox.send_move(2, text("2 at a")));
+        assert!(outbox.send_move(1, text("1 at b")));
+        // The newer position takes the place of the stale one in the queue
+        assert_eq!(queued(&rx), ["1 at b", "offer", "2 at a"]);
+        assert_eq!(counts(&stats), (3, 1, 0));
+    }
+
+    #[test]
+    fn other_kept() {
+        let stats = Arc::new(Stats::default());
+        let (outbox, rx) = outbox(0, 10, stats.clone());
+        assert!(outbox.send(text("candidate")));
+        assert!(outbox.send(text("candidate")));
+        assert_eq!(queued(&rx), ["candidate", "candidate"]);
+        assert_eq!(counts(&stats), (2, 0, 0));
+    }
+
+    #[test]
+    fn positions_pending() {
+        let rt = runtime::Builder::new_current_thread().build().unwrap();
+        let stats = Arc::new(Stats::default());
+        let (outbox, rx) = outbox(0, 10, stats);
+        assert!(!outbox.positions_pending());
+        assert!(outbox.send(text("offer")));
+        assert!(outbox.send_positions(text("positions")));
+        assert!(outbox.positions_pending());
+        // Still pending until the positions themselves have been taken off the queue
+        assert_eq!(rt.block_on(rx.next()).unwrap().to_text().unwrap(), "offer");
+        assert!(outbox.positions_pending());
+        assert_eq!(
+            rt.block_on(rx.next()).unwrap().to_text().unwrap(),
+            "positions"
+        );
+        assert!(!outbox.positions_pending());
+    }
+
+    #[test]
+    fn evicted() {
+        let stats = Arc::new(Stats::default());
+        let (outbox, rx) = outbox(0, 2, stats.clone());
+        assert!(outbox.send(text("a")));
+        assert!(outbox.send_move(1, text("b")));
+        // Coalesced moves do not grow the queue
+        assert!(outbox.send_move(1, text("c")));
+        assert!(!outbox.send(text("d")));
+        assert!(!outbox.send(text("e")));
+        assert_eq!(counts(&stats), (3, 1, 1));
+
+        let queue = rx.0.queue.lock().unwrap();
+        assert!(queue.closed && queue.messages.is_empty());
+        let reason = queue.reason.as_ref().unwrap();
+        assert_eq!(reason.code, CloseCode::Again);
+        assert_eq!(reason.reason, "Too slow to keep up with room");
+    }
+
+    #[test]
+    fn writer_gone() {
+        let stats = Arc::new(Stats::default());
+        let (outbox, rx) = outbox(0, 10, stats.clone());
+        drop(rx);
+        assert!(!outbox.send(text("a")));
+        assert!(!outbox.send_move(1, text("b")));
+        assert_eq!(counts(&stats), (0, 0, 0));
+    }
+}
@@ -0,0 +1,58 @@
+use super::message::Profile;
+use super::Config;
+
+/// Longest avatar URL or hash accepted, in bytes.
+const MAX_AVATAR_LENGTH: usize = 2048;
+
+/// Checks a profile a peer asked for, returning it with blank fields removed, or why it is not
+/// allowed.
+pub fn validate(profile: Profile, config: &Config) -> Result<Profile, String> {
+    let name = match profile.name.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(name) if name.chars().count() > config.max_name_length => {
+            return Err(format!(
+                "Names are limited to {} characters",
+                config.max_name_length
+            ));
+        }
+        Some(name) if name.chars().any(char::is_control) => {
+            return Err("Names may not contain control characters".to_owned());
+        }
+        Some(name) => Some(name.to_owned()),
+    };
+
+    let avatar = match profile.avatar.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(avatar) if avatar.len() > MAX_AVATAR_LENGTH => {
+            return Err(format!(
+                "Avatars are limited to {} bytes",
+                MAX_AVATAR_LENGTH
+            ));
+        }
+        Some(avatar) if is_url(avatar) || is_hash(avatar) => Some(avatar.to_owned()),
+        Some(_) => return Err("Avatars must be an http(s) URL or a hex hash".to_owned()),
+    };
+
+    let size = serde_json::to_vec(&profile.metadata).map_or(usize::MAX, |json| json.len());
+    if size > config.max_metadata_size {
+        return Err(format!(
+            "Metadata is limited to {} bytes of JSON",
+            config.max_metadata_size
+        ));
+    }
+
+    Ok(Profile {
+        name,
+        avatar,
+        metadata: profile.metadata,
+    })
+}
+
+fn is_url(avatar: &str) -> bool {
+    (avatar.starts_with("https://") || avatar.starts_with("http://"))
+        && !avatar.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn is_hash(avatar: &str) -> bool {
+    avatar.bytes().all(|b| b.is_ascii_hexdigit())
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+use super::message::ServerMessage;
+
+/// Subprotocol for the current protocol version. Clients that do not ask for it are assumed to
+/// speak version 1, from before versions were negotiated.
+pub const CURRENT: &str = "webrtc.v2";
+const VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Sessions can be resumed after a reconnect, using the secret from `Hello`
+    Resume,
+    /// Rejected messages are reported with an `Error` message
+    Errors,
+    /// Clients only connect to peers the server reports `InRange`, rather than to everyone, and
+    /// are only sent moves of peers near enough to come into range
+    Interest,
+    /// Moves are batched into one `Positions` message each tick, rather than sent as `MovePeer`
+    Positions,
+    /// Peers are told who is talking to whom, with `BubbleChanged`
+    Bubbles,
+    /// Peers can send each other text with `Chat`
+    Chat,
+    /// Room-wide chat is kept, and sent to peers as `History` when they join or ask for it
+    History,
+    /// Peers can set a name, avatar and metadata, and are told of changes with `PeerUpdated`
+    Profiles,
+    /// Peers share whether they are muted, away or speaking, with `Presence`
+    Presence,
+    /// Hosts can kick, ban, teleport and ask peers to mute, who are sent `MuteRequested`
+    Moderation,
+    /// Spectators can join without being placed on the map, and `Watch` peers that allow it
+    Spectators,
+    /// A peer can be given the stage, and be heard by everyone, with `Stage`
+    Stage,
+}
+
+/// Features of version 1, which clients that predate negotiation understand.
+const LEGACY: [Feature; 1] = [Feature::Resume];
+
+/// Features of the current version.
+const ALL: [Feature; 12] = [
+    Feature::Resume,
+    Feature::Errors,
+    Feature::Interest,
+    Feature::Positions,
+    Feature::Bubbles,
+    Feature::Chat,
+    Feature::History,
+    Feature::Profiles,
+    Feature::Presence,
+    Feature::Moderation,
+    Feature::Spectators,
+    Feature::Stage,
+];
+
+/// Protocol version and features agreed with a client, sent to it in `Hello`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: u32,
+    pub features: Vec<Feature>,
+}
+
+impl Protocol {
+    fn current() -> Self {
+        Protocol {
+            version: VERSION,
+            features: ALL.to_vec(),
+        }
+    }
+
+    /// Looks for the current version in a `Sec-WebSocket-Protocol` header, returning the
+    /// subprotocol to confirm to the client. Returns `None` if it is not offered.
+    pub fn negotiate(offered: &str) -> Option<(&'static str, Protocol)> {
+        offered
+            .split(',')
+            .map(str::trim)
+            .find(|&name| name == CURRENT)
+            .map(|_| (CURRENT, Protocol::current()))
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    /// Whether a client speaking this protocol understands `msg`. Clients are not sent messages
+    /// they do not.
+    pub fn accepts(&self, msg: &ServerMessage) -> bool {
+        match msg {
+            ServerMessage::Error { .. } => self.supports(Feature::Errors),
+            ServerMessage::InRange { .. } | ServerMessage::OutOfRange { .. } => {
+                self.supports(Feature::Interest)
+            }
+            ServerMessage::MovePeer { .. } => !self.supports(Feature::Positions),
+            ServerMessage::Positions { .. } => self.supports(Feature::Positions),
+            ServerMessage::BubbleChanged { .. } => self.supports(Feature::Bubbles),
+            ServerMessage::Chat { .. } => self.supports(Feature::Chat),
+            ServerMessage::History { .. } => self.supports(Feature::History),
+            ServerMessage::PeerUpdated { .. } => self.supports(Feature::Profiles),
+            ServerMessage::Presence { .. } => self.supports(Feature::Presence),
+            ServerMessage::MuteRequested { .. } => self.supports(Feature::Moderation),
+            ServerMessage::Stage { .. } => self.supports(Feature::Stage),
+            _ => true,
+        }
+    }
+}
+
+impl Default for Protocol {
+    /// The protocol spoken by clients that predate version negotiation.
+    fn default() -> Self {
+        Protocol {
+            version: 1,
+            features: LEGACY.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::message::Pos;
+    use super::*;
+
+    #[test]
+    fn negotiate() {
+        let (name, protocol) = Protocol::negotiate("chat, webrtc.v2").unwrap();
+        assert_eq!(name, CURRENT);
+        assert_eq!(protocol, Protocol::current());
+        assert!(Protocol::negotiate("webrtc.v1, webrtc.v3").is_none());
+        assert!(Protocol::negotiate("").is_none());
+    }
+
+    #[test]
+    fn legacy() {
+        let legacy = Protocol::default();
+        let pos = Pos { x: 1.0, y: 2.0 };
+        assert!(legacy.supports(Feature::Resume));
+        assert!(!legacy.supports(Feature::Interest));
+        assert!(legacy.accepts(&ServerMessage::MovePeer { peer: 1, pos }));
+        assert!(!legacy.accepts(&ServerMessage::OutOfRange { peer: 1 }));
+        assert!(!legacy.accepts(&ServerMessage::Positions {
+            positions: vec![(1, 1, 2)],
+            deltas: vec![],
+            zones: vec![],
+        }));
+    }
+
+    #[test]
+    fn current() {
+        let current = Protocol::current();
+        let pos = Pos { x: 1.0, y: 2.0 };
+        assert!(ALL.iter().all(|&feature| current.supports(feature)));
+        assert!(!current.accepts(&ServerMessage::MovePeer { peer: 1, pos }));
+        assert!(current.accepts(&ServerMessage::OutOfRange { peer: 1 }));
+    }
+}
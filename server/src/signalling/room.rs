@@ -0,0 +1,1507 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::channel::mpsc;
+use futures::{FutureExt, StreamExt};
+use tokio_tungstenite::tungstenite;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+
+use super::auth::{Claims, Role};
+use super::bubble::Bubbles;
+use super::grid::Grid;
+use super::handshake::Join;
+use super::history::{History, Writers};
+use super::interest::{Change, Interest, Subscriber};
+use super::limit::RateLimit;
+use super::message::{
+    self, ChatScope, ClientMessage, ErrorCode, PeerId, Pos, Presence, Profile, ProtocolError,
+    ServerMessage,
+};
+use super::outbox::{self, Outbox, Stats};
+use super::profile;
+use super::protocol::{Feature, Protocol};
+use super::{Config, Error};
+
+/// Commands sent to a room's task. Connections are identified by the server-wide connection
+/// number, which the room maps to the peer it is attached to.
+enum Command {
+    Join {
+        conn: usize,
+        join: Join,
+        outbox: Outbox,
+    },
+    /// The client closed the connection, and has left the room
+    Leave {
+        conn: usize,
+    },
+    /// The connection was lost, the peer may resume its session for a while
+    Disconnect {
+        conn: usize,
+    },
+    Message {
+        conn: usize,
+        message: ClientMessage,
+    },
+    /// The connection sent a message that could not be handled
+    Reject {
+        conn: usize,
+        error: ProtocolError,
+    },
+    /// The connection misbehaved, and its peer is removed from the room for good
+    Kick {
+        conn: usize,
+        reason: String,
+    },
+    /// The grace period for resuming a session over `conn` has passed
+    Expire {
+        id: PeerId,
+        conn: usize,
+    },
+    /// Time to send out the moves made since the last tick
+    Tick,
+}
+
+struct Member {
+    pos: Pos,
+    /// When the peer last moved, to limit its speed
+    moved_at: Instant,
+    /// How far away other peers come into range
+    radius: f32,
+    profile: Profile,
+    presence: Presence,
+    /// Limits how often changes to only the speaking flag are passed on
+    speaking_limit: RateLimit,
+    /// Verified token claims, if the server requires tokens
+    claims: Option<Claims>,
+    role: Role,
+    /// Spectators are not on the map, and only other spectators know they are in the room
+    spectating: bool,
+    /// Address of the most recent connection
+    addr: IpAddr,
+    /// Secret the peer must present to resume its session after losing its connection
+    secret: String,
+    /// The most recent connection the peer joined over
+    conn: usize,
+    /// The connection the peer first joined over. Of a pair coming into range, the peer that
+    /// joined first makes the offer, as legacy clients expect.
+    joined: usize,
+    /// The outbound queue of the connection, absent while waiting for the peer to resume
+    outbox: Option<Outbox>,
+    /// Malformed messages the peer may still send before it is disconnected
+    protocol_errors: RateLimit,
+    chat_limit: RateLimit,
+    protocol: Protocol,
+    /// The positions last sent to the peer in `Positions`, quantized
+    seen: HashMap<PeerId, (i32, i32)>,
+    /// The zones last sent to the peer in `Positions`
+    seen_zones: HashMap<PeerId, Option<usize>>,
+    /// Peers whose positions are held back until the last `Positions` has been sent
+    unsent: HashSet<PeerId>,
+    /// The map zone the peer is in
+    zone: Option<usize>,
+}
+
+struct Handle {
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+struct Shared {
+    rooms: Mutex<HashMap<String, Handle>>,
+    config: Config,
+    stats: Arc<Stats>,
+    /// Writers of each room's chat history, which may outlive the room
+    writers: Writers,
+}
+
+/// Registry of running rooms, each owned by its own task.
+#[derive(Clone)]
+pub struct Rooms(Arc<Shared>);
+
+impl Rooms {
+    pub fn new(config: Config) -> Self {
+        Rooms(Arc::new(Shared {
+            rooms: Mutex::new(HashMap::new()),
+            config,
+            stats: Arc::new(Stats::default()),
+            writers: Writers::default(),
+        }))
+    }
+
+    /// Outbound queue counters of all rooms, since the server started.
+    pub fn stats(&self) -> &Stats {
+        &self.0.stats
+    }
+
+    /// Whether rooms can be joined, which they cannot once a room panicked while holding the
+    /// list of rooms.
+    pub fn ready(&self) -> bool {
+        self.0.rooms.lock().is_ok()
+    }
+
+    /// Adds a connection to the room it asked for, starting the room's task if it is not yet
+    /// running.
+    ///
+    /// Returns the connection's handle on the room, and its outbound queue to be drained by a
+    /// writer task.
+    pub fn join(&self, conn: usize, join: Join) -> Result<(Membership, outbox::Receiver), Error> {
+        let mut rooms = self.0.rooms.lock()?;
+        let handle = rooms.entry(join.room.clone()).or_insert_with(|| {
+            let (tx, rx) = mpsc::unbounded();
+            let room = Room {
+                name: join.room.clone(),
+                rooms: self.clone(),
+                tx: tx.clone(),
+                peers: HashMap::new(),
+                connections: HashMap::new(),
+                interest: Interest::new(self.0.config.interest_hysteresis),
+                grid: Grid::new(self.0.config.interest_radius),
+                widest: self.0.config.interest_radius,
+                legacy: HashSet::new(),
+                moved: HashSet::new(),
+                arrived: HashSet::new(),
+                held_back: HashSet::new(),
+                spectators: HashSet::new(),
+                watching: HashMap::new(),
+                presence_pending: HashSet::new(),
+                occupants: HashMap::new(),
+                bubbles: Bubbles::new(self.0.config.talk_radius),
+                history: History::new(self.0.config.history_length),
+                stage: None,
+                hands: Vec::new(),
+                banned_subjects: HashSet::new(),
+                banned_addrs: HashSet::new(),
+            };
+            let (rooms, name) = (self.clone(), join.room.clone());
+            tokio::spawn(async move {
+                if let Err(e) = room.run(rx).await {
+                    eprintln!("Room {} failed: {:?}", name, e);
+                    if let Ok(mut rooms) = rooms.0.rooms.lock() {
+                        rooms.remove(&name);
+                    }
+                }
+            });
+            Handle { tx }
+        });
+
+        let (outbox, rx) = outbox::outbox(conn, self.0.config.outbox_limit, self.0.stats.clone());
+        // Sent with the registry locked, so the room cannot shut down before receiving it
+        handle
+            .tx
+            .unbounded_send(Command::Join { conn, join, outbox })
+            .map_err(|_| Error::RoomClosed)?;
+        let tx = handle.tx.clone();
+        Ok((Membership { conn, tx }, rx))
+    }
+}
+
+/// A connection's handle on its room. Dropping it without calling [`Membership::leave`] keeps
+/// the peer's place in the room for a while, so it can resume its session.
+pub struct Membership {
+    conn: usize,
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+impl Membership {
+    pub fn send(&self, message: ClientMessage) -> Result<(), Error> {
+        self.tx
+            .unbounded_send(Command::Message {
+                conn: self.conn,
+                message,
+            })
+            .map_err(|_| Error::RoomClosed)
+    }
+
+    /// Reports a message that could not be handled back to the client.
+    pub fn reject(&self, error: ProtocolError) -> Result<(), Error> {
+        self.tx
+            .unbounded_send(Command::Reject {
+                conn: self.conn,
+                error,
+            })
+            .map_err(|_| Error::RoomClosed)
+    }
+
+    /// Removes the peer from the room for good, closing its connection with `reason`.
+    pub fn kick(self, reason: &str) {
+        let _ = self.tx.unbounded_send(Command::Kick {
+            conn: self.conn,
+            reason: reason.to_owned(),
+        });
+    }
+
+    /// Leaves the room for good.
+    pub fn leave(self) {
+        let _ = self.tx.unbounded_send(Command::Leave { conn: self.conn });
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        // Ignored by the room if the peer has already left
+        let _ = self
+            .tx
+            .unbounded_send(Command::Disconnect { conn: self.conn });
+    }
+}
+
+fn encode(msg: &ServerMessage) -> Result<tungstenite::Message, Error> {
+    Ok(tungstenite::Message::Text(serde_json::to_string(msg)?))
+}
+
+/// Queues a message for each of `recipients` that understands it, and returns those whose
+/// outbox turned out to be closed.
+fn deliver<'a>(
+    recipients: impl Iterator<Item = (&'a PeerId, &'a Member)>,
+    msg: &ServerMessage,
+) -> Result<Vec<PeerId>, Error> {
+    let encoded = encode(msg)?;
+    let mut closed = Vec::new();
+    for (&id, peer) in recipients {
+        if let (Some(outbox), true) = (&peer.outbox, peer.protocol.accepts(msg)) {
+            if !outbox.send(encoded.clone()) {
+                closed.push(id);
+            }
+        }
+    }
+    Ok(closed)
+}
+
+/// Where a peer moving from `from` to `to` ends up, staying inside the room's walls and not
+/// moving faster than allowed.
+fn constrain(from: Pos, to: Pos, elapsed: Duration, config: &Config) -> Pos {
+    // Peers cannot save up time standing still for a teleport
+    let reach = config
+        .max_speed
+        .map(|speed| speed * elapsed.min(Duration::from_secs(1)).as_secs_f32());
+    let dist = (to.x - from.x).hypot(to.y - from.y);
+    let to = match reach {
+        Some(reach) if dist > reach => {
+            let scale = reach / dist;
+            Pos {
+                x: from.x + (to.x - from.x) * scale,
+                y: from.y + (to.y - from.y) * scale,
+            }
+        }
+        _ => to,
+    };
+    config.map.constrain(from, to)
+}
+
+/// Compares secrets in constant time.
+fn secrets_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+struct Room {
+    name: String,
+    rooms: Rooms,
+    /// For the room to schedule commands to itself
+    tx: mpsc::UnboundedSender<Command>,
+    peers: HashMap<PeerId, Member>,
+    /// The peer each open connection is attached to
+    connections: HashMap<usize, PeerId>,
+    interest: Interest,
+    /// Positions of all peers, including those waiting to resume
+    grid: Grid,
+    /// The largest radius any peer has picked, which is as far apart as a pair can come into
+    /// range
+    widest: f32,
+    /// Peers that predate interest management, and so are sent every move
+    legacy: HashSet<PeerId>,
+    /// Peers that have moved since the last tick
+    moved: HashSet<PeerId>,
+    /// Peers that joined or resumed since the last tick, who are sent everyone in view and sent to
+    /// them, zones included
+    arrived: HashSet<PeerId>,
+    /// Peers with positions held back while they catch up
+    held_back: HashSet<PeerId>,
+    spectators: HashSet<PeerId>,
+    /// The peers each spectator has a receive-only connection to
+    watching: HashMap<PeerId, HashSet<PeerId>>,
+    /// Peers whose presence changed since it was last sent, held back by their speaking limit
+    presence_pending: HashSet<PeerId>,
+    /// The peers in each private zone, who are in range of each other however far apart
+    occupants: HashMap<usize, HashSet<PeerId>>,
+    /// Who is talking to whom
+    bubbles: Bubbles,
+    /// Room-wide chat
+    history: History,
+    /// The peer heard by everyone, wherever they are
+    stage: Option<PeerId>,
+    /// Peers waiting for the stage, in the order they raised their hand
+    hands: Vec<PeerId>,
+    /// Users and addresses kept out of the room by a host
+    banned_subjects: HashSet<String>,
+    banned_addrs: HashSet<IpAddr>,
+}
+
+impl Room {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Command>) -> Result<(), Error> {
+        let config = &self.rooms.0.config;
+        if let Some(dir) = &config.history_dir {
+            let (dir, name) = (dir.clone(), self.name.clone());
+            let writers = &self.rooms.0.writers;
+            match History::open(dir, name, config.history_length, writers).await {
+                Ok(history) => self.history = history,
+                Err(e) => eprintln!("Could not load chat history of room {}: {:?}", self.name, e),
+            }
+        }
+
+        let (tx, tick) = (self.tx.clone(), config.tick);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tick);
+            loop {
+                interval.tick().await;
+                // Fails once the room has shut down
+                if tx.unbounded_send(Command::Tick).is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Some(cmd) = rx.next().await {
+            self.handle(cmd)?;
+
+            while self.peers.is_empty() {
+                // Joins are only sent with the registry locked, so none can arrive once we are
+                // unregistered
+                let mut rooms = self.rooms.0.rooms.lock()?;
+                match rx.next().now_or_never() {
+                    Some(Some(cmd)) => {
+                        drop(rooms);
+                        self.handle(cmd)?;
+                    }
+                    _ => {
+                        rooms.remove(&self.name);
+                        eprintln!("Room {} closed: {:?}", self.name, self.rooms.0.stats);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, cmd: Command) -> Result<(), Error> {
+        match cmd {
+            Command::Join { conn, join, outbox } => self.join(conn, join, outbox),
+            Command::Leave { conn } => match self.connections.remove(&conn) {
+                Some(id) => self.leave(id),
+                None => Ok(()),
+            },
+            Command::Disconnect { conn } => {
+                if let Some(id) = self.connections.remove(&conn) {
+                    self.disconnect(id);
+                }
+                Ok(())
+            }
+            Command::Message { conn, message } => match self.connections.get(&conn) {
+                Some(&id) => self.message(id, message),
+                None => Ok(()),
+            },
+            Command::Reject { conn, error } => match self.connections.get(&conn) {
+                Some(&id) => self.reject(id, error),
+                None => Ok(()),
+            },
+            Command::Kick { conn, reason } => match self.connections.get(&conn) {
+                Some(&id) => {
+                    eprintln!("Removing peer {} from room {}: {}", id, self.name, reason);
+                    self.kick(id, &reason)
+                }
+                None => Ok(()),
+            },
+            Command::Expire { id, conn } => {
+                let expired = self
+                    .peers
+                    .get(&id)
+                    .map_or(false, |peer| peer.conn == conn && peer.outbox.is_none());
+                if expired {
+                    self.leave(id)
+                } else {
+                    Ok(())
+                }
+            }
+            Command::Tick => self.tick(),
+        }
+    }
+
+    /// Finds the peer a connection may take over, if it presented the secret for a session that
+    /// has not yet expired.
+    fn resumable(&self, join: &Join) -> Option<PeerId> {
+        let (id, secret) = join.resume.as_ref()?;
+        let peer = self.peers.get(id)?;
+        let same_user = match (&peer.claims, &join.claims) {
+            (Some(a), Some(b)) => a.sub == b.sub,
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        if same_user && secrets_match(&peer.secret, secret) {
+            Some(*id)
+        } else {
+            None
+        }
+    }
+
+    fn banned(&self, join: &Join) -> bool {
+        let subject = join.claims.as_ref().map(|claims| &claims.sub);
+        self.banned_addrs.contains(&join.addr)
+            || subject.map_or(false, |sub| self.banned_subjects.contains(sub))
+    }
+
+    fn join(&mut self, conn: usize, join: Join, outbox: Outbox) -> Result<(), Error> {
+        if self.banned(&join) {
+            outbox.close(CloseFrame {
+                code: CloseCode::Policy,
+                reason: "Banned from this room".into(),
+            });
+            return Ok(());
+        }
+        if let Some(id) = self.resumable(&join) {
+            return self.resume(conn, id, join, outbox);
+        }
+
+        let id = loop {
+            // Kept to integers a JavaScript number can represent exactly
+            let id = rand::random::<PeerId>() >> 11;
+            if !self.peers.contains_key(&id) {
+                break id;
+            }
+        };
+
+        if let Some(claims) = &join.claims {
+            eprintln!(
+                "Peer {} joined room {} as {} ({:?})",
+                id, self.name, claims.sub, claims.role
+            );
+        }
+
+        let secret = format!("{:032x}", rand::random::<u128>());
+        let protocol = join.protocol.clone();
+        let role = join
+            .claims
+            .as_ref()
+            .map_or(Role::Member, |claims| claims.role);
+        let spectating = join.spectate || role == Role::Spectator;
+        let pos = if spectating {
+            Pos::default()
+        } else {
+            self.rooms.0.config.map.spawn()
+        };
+
+        self.peers.insert(
+            id,
+            Member {
+                pos,
+                moved_at: Instant::now(),
+                radius: self.rooms.0.config.interest_radius,
+                profile: Profile::default(),
+                presence: Presence::default(),
+                speaking_limit: RateLimit::new(
+                    self.rooms.0.config.speaking_burst,
+                    self.rooms.0.config.speaking_interval,
+                ),
+                claims: join.claims,
+                role,
+                spectating,
+                addr: join.addr,
+                secret,
+                conn,
+                joined: conn,
+                outbox: Some(outbox),
+                protocol_errors: RateLimit::new(
+                    self.rooms.0.config.max_protocol_errors,
+                    self.rooms.0.config.protocol_error_interval,
+                ),
+                chat_limit: RateLimit::new(
+                    self.rooms.0.config.chat_burst,
+                    self.rooms.0.config.chat_interval,
+                ),
+                protocol: join.protocol,
+                seen: HashMap::new(),
+                seen_zones: HashMap::new(),
+                unsent: HashSet::new(),
+                zone: None,
+            },
+        );
+        self.connections.insert(conn, id);
+        self.set_legacy(id);
+        if spectating {
+            self.spectators.insert(id);
+        } else {
+            self.grid.insert(id, pos);
+            self.set_zone(id);
+            self.bubbles.moved(id, pos);
+            self.arrived.insert(id);
+        }
+
+        let hello = ServerMessage::Hello {
+            state: self.state(id),
+            peers: self.hello_peers(id),
+            resume: self.peers[&id].secret.clone(),
+            protocol,
+            quantum: self.rooms.0.config.position_quantum,
+        };
+        self.send(id, &hello)?;
+        self.send_bubbles(id)?;
+        self.send(id, &self.stage())?;
+        self.send_history(id, None, None)?;
+        if spectating {
+            return Ok(());
+        }
+        self.broadcast(
+            &ServerMessage::AddPeer {
+                peer: self.state(id),
+            },
+            Some(id),
+        )?;
+        self.update_interest(id)
+    }
+
+    /// Attaches a new connection to an existing peer, without telling the other peers.
+    fn resume(&mut self, conn: usize, id: PeerId, join: Join, outbox: Outbox) -> Result<(), Error> {
+        let protocol = join.protocol;
+        let hello = ServerMessage::Hello {
+            state: self.state(id),
+            peers: self.hello_peers(id),
+            resume: self.peers[&id].secret.clone(),
+            protocol: protocol.clone(),
+            quantum: self.rooms.0.config.position_quantum,
+        };
+
+        let peer = self.peers.get_mut(&id).unwrap();
+        // A previous connection may not yet have noticed it was lost
+        self.connections.remove(&peer.conn);
+        peer.conn = conn;
+        peer.outbox = Some(outbox);
+        peer.protocol = protocol;
+        peer.addr = join.addr;
+        // Updates queued for the old connection may have been lost, so positions are sent in full
+        peer.seen.clear();
+        peer.seen_zones.clear();
+        if !peer.spectating {
+            self.arrived.insert(id);
+        }
+        self.connections.insert(conn, id);
+        self.set_legacy(id);
+        self.send(id, &hello)?;
+        self.send_bubbles(id)?;
+        self.send(id, &self.stage())?;
+        // Messages sent while the peer was away were lost with the old connection
+        self.send_history(id, None, None)?;
+        // Pairs were left as they were while the peer was away
+        self.update_interest(id)
+    }
+
+    fn hello_peers(&self, id: PeerId) -> Vec<message::Peer> {
+        self.peers
+            .iter()
+            .filter(|&(&peer_id, peer)| peer_id != id && !peer.spectating)
+            .map(|(&id, _)| self.state(id))
+            .collect()
+    }
+
+    fn state(&self, id: PeerId) -> message::Peer {
+        let peer = &self.peers[&id];
+        message::Peer {
+            id,
+            pos: peer.pos,
+            zone: self.zone_name(peer.zone),
+            profile: peer.profile.clone(),
+            presence: peer.presence,
+            role: peer.role,
+            spectator: peer.spectating,
+        }
+    }
+
+    fn zone_name(&self, zone: Option<usize>) -> Option<String> {
+        zone.map(|zone| self.rooms.0.config.map.zones[zone].name.clone())
+    }
+
+    /// Updates which zone a peer is in, after it has moved.
+    fn set_zone(&mut self, id: PeerId) {
+        let map = &self.rooms.0.config.map;
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let zone = map.zone_at(peer.pos);
+        let old = std::mem::replace(&mut peer.zone, zone);
+        if old == zone {
+            return;
+        }
+        if let Some(old) = old {
+            if let Some(occupants) = self.occupants.get_mut(&old) {
+                occupants.remove(&id);
+            }
+        }
+        if let Some(zone) = zone.filter(|&zone| map.zones[zone].private) {
+            self.occupants.entry(zone).or_default().insert(id);
+        }
+    }
+
+    /// Tells a peer that just connected about the conversations already going on.
+    fn send_bubbles(&mut self, id: PeerId) -> Result<(), Error> {
+        let bubbles = self
+            .bubbles
+            .all()
+            .map(|(bubble, members)| ServerMessage::BubbleChanged {
+                bubble,
+                members: members.iter().copied().collect(),
+            })
+            .collect::<Vec<_>>();
+        for msg in &bubbles {
+            self.send(id, msg)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a page of room-wide chat, the latest unless `before` is given.
+    fn send_history(
+        &mut self,
+        id: PeerId,
+        before: Option<u64>,
+        limit: Option<usize>,
+    ) -> Result<(), Error> {
+        let page = self.rooms.0.config.history_page;
+        let limit = limit.map_or(page, |limit| limit.min(page));
+        let (messages, more) = self.history.page(before, limit);
+        self.send(id, &ServerMessage::History { messages, more })
+    }
+
+    fn set_legacy(&mut self, id: PeerId) {
+        match self.peers.get(&id) {
+            Some(peer) if !peer.protocol.supports(Feature::Interest) => self.legacy.insert(id),
+            _ => self.legacy.remove(&id),
+        };
+    }
+
+    /// The peers on the map close enough to `id` that they may be in range of each other, and so
+    /// are sent each other's positions.
+    fn in_view(&self, id: PeerId) -> Vec<PeerId> {
+        let peer = match self.peers.get(&id) {
+            Some(peer) => peer,
+            None => return Vec::new(),
+        };
+        let hysteresis = self.rooms.0.config.interest_hysteresis;
+        let mut in_view = self.grid.query(peer.pos, self.widest + hysteresis);
+        in_view.retain(|other| {
+            self.peers.get(other).map_or(false, |other| {
+                let reach = peer.radius.max(other.radius) + hysteresis;
+                (other.pos.x - peer.pos.x).hypot(other.pos.y - peer.pos.y) <= reach
+            })
+        });
+        in_view
+    }
+
+    /// Changes how far a peer wants to hear, keeping track of the widest radius in the room.
+    fn set_radius(&mut self, id: PeerId, radius: f32) {
+        let config = &self.rooms.0.config;
+        let radius = radius.max(0.0).min(config.max_interest_radius);
+        let old = match self.peers.get_mut(&id) {
+            Some(peer) => std::mem::replace(&mut peer.radius, radius),
+            None => return,
+        };
+        if radius >= self.widest {
+            self.widest = radius;
+        } else if old >= self.widest {
+            self.update_widest();
+        }
+    }
+
+    /// Finds the widest radius again, once the peer that had it has shrunk it or left.
+    fn update_widest(&mut self) {
+        let default = self.rooms.0.config.interest_radius;
+        self.widest = self
+            .peers
+            .values()
+            .map(|peer| peer.radius)
+            .fold(default, f32::max);
+    }
+
+    fn subscriber(&self, id: PeerId) -> Option<Subscriber> {
+        let peer = self.peers.get(&id).filter(|peer| !peer.spectating)?;
+        peer.outbox.as_ref()?;
+        Some(Subscriber {
+            id,
+            pos: peer.pos,
+            radius: peer.radius,
+            pinned: !peer.protocol.supports(Feature::Interest) || self.stage == Some(id),
+            private: peer
+                .zone
+                .filter(|&zone| self.rooms.0.config.map.zones[zone].private),
+        })
+    }
+
+    /// Rechecks which peers are in range of `id`, telling both sides of each pair that changed.
+    /// Peers waiting to resume their session are skipped, keeping their pairs as they were.
+    fn update_interest(&mut self, id: PeerId) -> Result<(), Error> {
+        let subscriber = match self.subscriber(id) {
+            Some(subscriber) => subscriber,
+            None => return Ok(()),
+        };
+        // Legacy peers are in range of everyone, otherwise only nearby peers can come into range
+        let candidates = if subscriber.pinned {
+            self.peers.keys().copied().collect::<HashSet<_>>()
+        } else {
+            let mut candidates = self.grid.query(subscriber.pos, self.widest);
+            candidates.extend(self.interest.neighbours(id));
+            candidates.extend(&self.legacy);
+            candidates.extend(self.stage);
+            if let Some(occupants) = subscriber
+                .private
+                .and_then(|zone| self.occupants.get(&zone))
+            {
+                candidates.extend(occupants);
+            }
+            candidates.into_iter().collect()
+        };
+        let others = candidates
+            .into_iter()
+            .filter_map(|other| self.subscriber(other))
+            .collect::<Vec<_>>();
+
+        for change in self.interest.update(subscriber, others) {
+            let (a, b, entered) = match change {
+                Change::Enter(a, b) => (a, b, true),
+                Change::Leave(a, b) => (a, b, false),
+            };
+            for &(id, peer) in &[(a, b), (b, a)] {
+                let msg = if entered {
+                    let polite = self.peers[&id].joined > self.peers[&peer].joined;
+                    ServerMessage::InRange {
+                        peer,
+                        polite,
+                        receive_only: false,
+                    }
+                } else {
+                    ServerMessage::OutOfRange { peer }
+                };
+                self.send(id, &msg)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps a peer whose connection was lost in the room until its grace period passes.
+    fn disconnect(&mut self, id: PeerId) {
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        self.connections.remove(&peer.conn);
+        peer.outbox = None;
+
+        let (tx, conn) = (self.tx.clone(), peer.conn);
+        let grace = self.rooms.0.config.resume_grace;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            let _ = tx.unbounded_send(Command::Expire { id, conn });
+        });
+    }
+
+    fn leave(&mut self, id: PeerId) -> Result<(), Error> {
+        if let Some(peer) = self.peers.remove(&id) {
+            self.connections.remove(&peer.conn);
+            self.interest.remove(id);
+            self.grid.remove(id);
+            self.legacy.remove(&id);
+            self.moved.remove(&id);
+            self.arrived.remove(&id);
+            self.held_back.remove(&id);
+            self.presence_pending.remove(&id);
+            self.spectators.remove(&id);
+            self.bubbles.remove(id);
+            if peer.radius >= self.widest {
+                self.update_widest();
+            }
+            if let Some(occupants) = peer.zone.and_then(|zone| self.occupants.get_mut(&zone)) {
+                occupants.remove(&id);
+            }
+            for peer in self.peers.values_mut() {
+                peer.seen.remove(&id);
+                peer.seen_zones.remove(&id);
+                peer.unsent.remove(&id);
+            }
+            if let Some(claims) = peer.claims {
+                eprintln!("Peer {} ({}) left room {}", id, claims.sub, self.name);
+            }
+            // Only spectators know about other spectators, and only those they watch about them
+            for target in self.watching.remove(&id).unwrap_or_default() {
+                self.send(target, &ServerMessage::OutOfRange { peer: id })?;
+            }
+            if !peer.spectating {
+                for targets in self.watching.values_mut() {
+                    targets.remove(&id);
+                }
+                self.broadcast(&ServerMessage::RemovePeer { peer: id }, None)?;
+            }
+            let hands = self.hands.len();
+            self.hands.retain(|&hand| hand != id);
+            if self.stage == Some(id) || self.hands.len() != hands {
+                self.stage = self.stage.filter(|&stage| stage != id);
+                self.broadcast(&self.stage(), None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a peer may send signalling messages to `target`. Spectators are hidden from
+    /// everyone but the peers they watch.
+    fn may_signal(&self, id: PeerId, target: PeerId) -> bool {
+        let watches = |a, b| {
+            self.watching
+                .get(&a)
+                .map_or(false, |targets: &HashSet<PeerId>| targets.contains(&b))
+        };
+        let hidden = self.spectators.contains(&id) || self.spectators.contains(&target);
+        !hidden || watches(id, target) || watches(target, id)
+    }
+
+    /// Opens a receive-only connection from a spectator to a peer that allows it.
+    fn watch(&mut self, id: PeerId, target: PeerId) -> Result<(), Error> {
+        let error = |code, message: String| ProtocolError {
+            code,
+            message,
+            in_reply_to: Some("Watch".to_owned()),
+        };
+        if !self.spectators.contains(&id) {
+            let message = "Only spectators may watch peers".to_owned();
+            return self.reject(id, error(ErrorCode::Forbidden, message));
+        }
+        match self.peers.get(&target) {
+            Some(peer) if !peer.spectating && peer.presence.allow_spectators => {}
+            Some(_) => {
+                let message = format!("Peer {} does not allow spectators", target);
+                return self.reject(id, error(ErrorCode::Forbidden, message));
+            }
+            None => {
+                let message = format!("No peer {} in room", target);
+                return self.reject(id, error(ErrorCode::UnknownPeer, message));
+            }
+        }
+        if !self.watching.entry(id).or_default().insert(target) {
+            return Ok(());
+        }
+
+        // The watched peer makes the offer, with its media
+        let msg = ServerMessage::InRange {
+            peer: id,
+            polite: false,
+            receive_only: true,
+        };
+        self.send(target, &msg)?;
+        let msg = ServerMessage::InRange {
+            peer: target,
+            polite: true,
+            receive_only: true,
+        };
+        self.send(id, &msg)
+    }
+
+    fn unwatch(&mut self, id: PeerId, target: PeerId) -> Result<(), Error> {
+        let removed = self
+            .watching
+            .get_mut(&id)
+            .map_or(false, |targets| targets.remove(&target));
+        if !removed {
+            return Ok(());
+        }
+        self.send(target, &ServerMessage::OutOfRange { peer: id })?;
+        self.send(id, &ServerMessage::OutOfRange { peer: target })
+    }
+
+    fn stage(&self) -> ServerMessage {
+        ServerMessage::Stage {
+            peer: self.stage,
+            hands: self.hands.clone(),
+        }
+    }
+
+    /// Gives the stage to `target`, or takes it away. Hosts may give it to anyone on the map,
+    /// and whoever is on stage may hand it to a peer with their hand raised.
+    fn set_stage(&mut self, id: PeerId, target: Option<PeerId>) -> Result<(), Error> {
+        let error = |code, message: String| ProtocolError {
+            code,
+            message,
+            in_reply_to: Some("SetStage".to_owned()),
+        };
+        let host = self.peers.get(&id).map(|peer| peer.role) == Some(Role::Host);
+        let handover =
+            self.stage == Some(id) && target.map_or(true, |target| self.hands.contains(&target));
+        if !host && !handover {
+            let message = "Only hosts may give out the stage".to_owned();
+            return self.reject(id, error(ErrorCode::Forbidden, message));
+        }
+        if let Some(target) = target {
+            match self.peers.get(&target) {
+                Some(peer) if peer.spectating => {
+                    let message = "Spectators cannot take the stage".to_owned();
+                    return self.reject(id, error(ErrorCode::Forbidden, message));
+                }
+                Some(_) => {}
+                None => {
+                    let message = format!("No peer {} in room", target);
+                    return self.reject(id, error(ErrorCode::UnknownPeer, message));
+                }
+            }
+        }
+
+        let old = std::mem::replace(&mut self.stage, target);
+        self.hands.retain(|&hand| Some(hand) != target);
+        self.broadcast(&self.stage(), None)?;
+        // Everyone connects to the new holder, and drops the old one again if it is too far away
+        for id in old.into_iter().chain(target) {
+            self.update_interest(id)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a peer to the queue for the stage, or takes it off if `raised` is not set.
+    fn raise_hand(&mut self, id: PeerId, raised: bool) -> Result<(), Error> {
+        if self.spectators.contains(&id) {
+            let error = ProtocolError {
+                code: ErrorCode::Forbidden,
+                message: "Spectators cannot take the stage".to_owned(),
+                in_reply_to: Some(if raised { "RaiseHand" } else { "LowerHand" }.to_owned()),
+            };
+            return self.reject(id, error);
+        }
+        let queued = self.hands.contains(&id);
+        if raised && !queued && self.stage != Some(id) {
+            self.hands.push(id);
+        } else if !raised && queued {
+            self.hands.retain(|&hand| hand != id);
+        } else {
+            return Ok(());
+        }
+        self.broadcast(&self.stage(), None)
+    }
+
+    fn message(&mut self, id: PeerId, message: ClientMessage) -> Result<(), Error> {
+        match message {
+            ClientMessage::Peer { message: msg } => {
+                let target = msg.peer;
+                if !self.peers.contains_key(&target) || !self.may_signal(id, target) {
+                    return self.reject(
+                        id,
+                        ProtocolError {
+                            code: ErrorCode::UnknownPeer,
+                            message: format!("No peer {} in room", target),
+                            in_reply_to: Some("Peer".to_owned()),
+                        },
+                    );
+                }
+                self.send(target, &msg.forward(id))
+            }
+            ClientMessage::Move { .. } | ClientMessage::SetRadius { .. }
+                if self.spectators.contains(&id) =>
+            {
+                let error = ProtocolError {
+                    code: ErrorCode::Forbidden,
+                    message: "Spectators are not on the map".to_owned(),
+                    in_reply_to: Some(message.type_name().to_owned()),
+                };
+                self.reject(id, error)
+            }
+            ClientMessage::Move { pos } => {
+                if !pos.x.is_finite() || !pos.y.is_finite() {
+                    return self.reject(
+                        id,
+                        ProtocolError {
+                            code: ErrorCode::InvalidMessage,
+                            message: "Position must be finite".to_owned(),
+                            in_reply_to: Some("Move".to_owned()),
+                        },
+                    );
+                }
+
+                let peer = match self.peers.get(&id) {
+                    Some(peer) => peer,
+                    None => return Ok(()),
+                };
+                let elapsed = peer.moved_at.elapsed();
+                let allowed = constrain(peer.pos, pos, elapsed, &self.rooms.0.config);
+                // The mover is sent where it actually ended up if it was stopped short
+                self.place(id, allowed, (allowed.x, allowed.y) != (pos.x, pos.y));
+                Ok(())
+            }
+            ClientMessage::SetRadius { radius } => {
+                self.set_radius(id, radius);
+                self.update_interest(id)
+            }
+            ClientMessage::Presence { presence } => self.set_presence(id, presence),
+            ClientMessage::Watch { peer } => self.watch(id, peer),
+            ClientMessage::Unwatch { peer } => self.unwatch(id, peer),
+            ClientMessage::SetStage { peer } => self.set_stage(id, peer),
+            ClientMessage::RaiseHand => self.raise_hand(id, true),
+            ClientMessage::LowerHand => self.raise_hand(id, false),
+            ClientMessage::Kick { peer } => match self.authorize(id, peer, "Kick") {
+                Ok(()) => {
+                    eprintln!("Peer {} kicked from room {} by {}", peer, self.name, id);
+                    self.kick(peer, "Kicked by the host")
+                }
+                Err(e) => self.reject(id, e),
+            },
+            ClientMessage::RequestMute { peer } => match self.authorize(id, peer, "RequestMute") {
+                Ok(()) => self.send(peer, &ServerMessage::MuteRequested { by: id }),
+                Err(e) => self.reject(id, e),
+            },
+            ClientMessage::Ban { peer, ip } => match self.authorize(id, peer, "Ban") {
+                Ok(()) => {
+                    let target = &self.peers[&peer];
+                    let subject = target.claims.as_ref().map(|claims| claims.sub.clone());
+                    if ip || subject.is_none() {
+                        // Banning the proxy, or this machine, would keep out everyone behind it
+                        let addr = target.addr;
+                        let config = &self.rooms.0.config;
+                        if addr.is_loopback() || config.trusted_proxies.contains(&addr) {
+                            let error = ProtocolError {
+                                code: ErrorCode::Forbidden,
+                                message: "The peer's address is a proxy's, so cannot be banned"
+                                    .to_owned(),
+                                in_reply_to: Some("Ban".to_owned()),
+                            };
+                            return self.reject(id, error);
+                        }
+                        self.banned_addrs.insert(addr);
+                    }
+                    if let Some(subject) = subject {
+                        self.banned_subjects.insert(subject);
+                    }
+                    eprintln!("Peer {} banned from room {} by {}", peer, self.name, id);
+                    self.kick(peer, "Banned by the host")
+                }
+                Err(e) => self.reject(id, e),
+            },
+            ClientMessage::Teleport { peer, pos } => {
+                if let Err(e) = self.authorize(id, peer, "Teleport") {
+                    return self.reject(id, e);
+                }
+                if self.spectators.contains(&peer) {
+                    let error = ProtocolError {
+                        code: ErrorCode::Forbidden,
+                        message: "Spectators are not on the map".to_owned(),
+                        in_reply_to: Some("Teleport".to_owned()),
+                    };
+                    return self.reject(id, error);
+                }
+                if !pos.x.is_finite() || !pos.y.is_finite() {
+                    return self.reject(
+                        id,
+                        ProtocolError {
+                            code: ErrorCode::InvalidMessage,
+                            message: "Position must be finite".to_owned(),
+                            in_reply_to: Some("Teleport".to_owned()),
+                        },
+                    );
+                }
+                // Kept inside the room, but may pass through walls
+                let pos = self.rooms.0.config.map.constrain(pos, pos);
+                self.place(peer, pos, true);
+                Ok(())
+            }
+            ClientMessage::SetProfile { profile } => {
+                let profile = match profile::validate(profile, &self.rooms.0.config) {
+                    Ok(profile) => profile,
+                    Err(message) => {
+                        let error = ProtocolError {
+                            code: ErrorCode::InvalidMessage,
+                            message,
+                            in_reply_to: Some("SetProfile".to_owned()),
+                        };
+                        return self.reject(id, error);
+                    }
+                };
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.profile = profile.clone();
+                }
+                // Echoed to the peer too, as it may have been tidied up
+                self.announce(id, &ServerMessage::PeerUpdated { peer: id, profile }, true)
+            }
+            ClientMessage::Chat { scope, text } => self.chat(id, scope, text),
+            ClientMessage::History { before, limit } => self.send_history(id, before, limit),
+            // Rejected when parsed
+            ClientMessage::Unknown => Ok(()),
+        }
+    }
+
+    /// Moves a peer, to be sent out on the next tick. The peer itself is sent its new position
+    /// too if `correct` is set, as it may not know it.
+    fn place(&mut self, id: PeerId, pos: Pos, correct: bool) {
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        if correct {
+            peer.seen.remove(&id);
+        }
+        peer.pos = pos;
+        peer.moved_at = Instant::now();
+        self.grid.insert(id, pos);
+        self.set_zone(id);
+        self.bubbles.moved(id, pos);
+        self.moved.insert(id);
+    }
+
+    /// Checks a peer may moderate `target`. Only hosts may, and not other hosts.
+    fn authorize(&self, id: PeerId, target: PeerId, ty: &str) -> Result<(), ProtocolError> {
+        let error = |code, message: String| ProtocolError {
+            code,
+            message,
+            in_reply_to: Some(ty.to_owned()),
+        };
+        if self.peers.get(&id).map(|peer| peer.role) != Some(Role::Host) {
+            return Err(error(
+                ErrorCode::Forbidden,
+                "Only hosts may do this".to_owned(),
+            ));
+        }
+        match self.peers.get(&target) {
+            None => Err(error(
+                ErrorCode::UnknownPeer,
+                format!("No peer {} in room", target),
+            )),
+            Some(peer) if peer.role == Role::Host => Err(error(
+                ErrorCode::Forbidden,
+                "Hosts cannot be moderated".to_owned(),
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Removes a peer from the room for good, closing its connection with `reason`.
+    fn kick(&mut self, id: PeerId, reason: &str) -> Result<(), Error> {
+        if let Some(outbox) = self.peers.get(&id).and_then(|peer| peer.outbox.as_ref()) {
+            outbox.close(CloseFrame {
+                code: CloseCode::Policy,
+                reason: reason.to_owned().into(),
+            });
+        }
+        self.leave(id)
+    }
+
+    /// Records a peer's presence, and tells the others. Changes to only the speaking flag are
+    /// held back for a later tick if the peer is sending them too often.
+    fn set_presence(&mut self, id: PeerId, presence: Presence) -> Result<(), Error> {
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let old = std::mem::replace(&mut peer.presence, presence);
+        if old == presence {
+            return Ok(());
+        }
+        if old.allow_spectators && !presence.allow_spectators {
+            let watchers = self
+                .watching
+                .iter()
+                .filter(|(_, targets)| targets.contains(&id))
+                .map(|(&spectator, _)| spectator)
+                .collect::<Vec<_>>();
+            for spectator in watchers {
+                self.unwatch(spectator, id)?;
+            }
+        }
+        let peer = self.peers.get_mut(&id).unwrap();
+        if presence.only_speaking_changed(&old) && !peer.speaking_limit.take() {
+            self.presence_pending.insert(id);
+            return Ok(());
+        }
+
+        self.presence_pending.remove(&id);
+        self.announce(id, &ServerMessage::Presence { peer: id, presence }, false)
+    }
+
+    /// Tells the room about a change to a peer, and the peer itself if `echo` is set. Nobody else
+    /// knows about spectators, so changes to them are only echoed.
+    fn announce(&mut self, id: PeerId, msg: &ServerMessage, echo: bool) -> Result<(), Error> {
+        if self.spectators.contains(&id) {
+            return if echo { self.send(id, msg) } else { Ok(()) };
+        }
+        self.broadcast(msg, if echo { None } else { Some(id) })
+    }
+
+    fn send_pending_presence(&mut self) -> Result<(), Error> {
+        let pending = self.presence_pending.iter().copied().collect::<Vec<_>>();
+        for id in pending {
+            let peer = match self.peers.get_mut(&id) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            if !peer.speaking_limit.take() {
+                continue;
+            }
+            let presence = peer.presence;
+            self.presence_pending.remove(&id);
+            self.announce(id, &ServerMessage::Presence { peer: id, presence }, false)?;
+        }
+        Ok(())
+    }
+
+    /// Sends out the moves made since the last tick, and updates which peers are in range and who
+    /// is talking to whom.
+    fn tick(&mut self) -> Result<(), Error> {
+        for (bubble, members) in self.bubbles.update() {
+            self.broadcast(&ServerMessage::BubbleChanged { bubble, members }, None)?;
+        }
+        self.send_pending_presence()?;
+
+        if self.moved.is_empty() && self.arrived.is_empty() && self.held_back.is_empty() {
+            return Ok(());
+        }
+        let moved = std::mem::take(&mut self.moved);
+        let arrived = std::mem::take(&mut self.arrived);
+
+        // Only the positions and zones of peers that moved can have changed, so each peer is sent
+        // those of the peers in view that moved, and each peer that moved those of everyone now in
+        // view. Spectators are not on the map, so see all of it
+        let mut batches = HashMap::<PeerId, HashSet<PeerId>>::new();
+        let mut exchange = |id: PeerId, recipients: &[PeerId]| {
+            for &peer in recipients {
+                batches.entry(peer).or_default().insert(id);
+            }
+            batches.entry(id).or_default().extend(recipients);
+        };
+        for &id in &arrived {
+            exchange(id, &self.in_view(id));
+        }
+        for &id in &moved {
+            self.update_interest(id)?;
+
+            let pos = self.peers[&id].pos;
+            let mut recipients = self.in_view(id);
+            exchange(id, &recipients);
+
+            // Clients without `Positions` are sent a `MovePeer` for each peer that moved
+            recipients.retain(|peer| !self.legacy.contains(peer));
+            recipients.extend(&self.legacy);
+            let msg = ServerMessage::MovePeer { peer: id, pos };
+            let encoded = encode(&msg)?;
+            let mut closed = Vec::new();
+            for (&peer_id, peer) in recipients
+                .iter()
+                .filter_map(|peer| self.peers.get_key_value(peer))
+            {
+                if let (Some(outbox), true) = (&peer.outbox, peer.protocol.accepts(&msg)) {
+                    if !outbox.send_move(id, encoded.clone()) {
+                        closed.push(peer_id);
+                    }
+                }
+            }
+            for peer in closed {
+                self.evict(peer);
+            }
+        }
+
+        for id in std::mem::take(&mut self.held_back) {
+            batches.entry(id).or_default();
+        }
+        for &id in &self.spectators {
+            batches.entry(id).or_default().extend(moved.union(&arrived));
+        }
+        for (id, batch) in batches {
+            self.send_positions(id, batch)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a peer the positions of those in `batch` that have changed since it was last told.
+    ///
+    /// While its last `Positions` has not been sent, the batch is held back to be sent with the
+    /// next, so a peer that has stalled is not sent a message per tick.
+    fn send_positions(&mut self, id: PeerId, mut batch: HashSet<PeerId>) -> Result<(), Error> {
+        let (quantum, deltas) = {
+            let config = &self.rooms.0.config;
+            (config.position_quantum, config.position_deltas)
+        };
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) if peer.protocol.supports(Feature::Positions) => peer,
+            _ => return Ok(()),
+        };
+        match &peer.outbox {
+            Some(outbox) if outbox.positions_pending() => {
+                peer.unsent.extend(batch);
+                self.held_back.insert(id);
+                return Ok(());
+            }
+            Some(_) => batch.extend(peer.unsent.drain()),
+            None => return Ok(()),
+        }
+        let current = batch
+            .into_iter()
+            .filter_map(|other| {
+                let other_peer = self.peers.get(&other).filter(|peer| !peer.spectating)?;
+                Some((other, other_peer.pos.quantize(quantum), other_peer.zone))
+            })
+            .collect::<Vec<_>>();
+
+        let peer = self.peers.get_mut(&id).unwrap();
+        let (mut positions, mut changes, mut zones) = (Vec::new(), Vec::new(), Vec::new());
+        for (other, (x, y), zone) in current {
+            match peer.seen.insert(other, (x, y)) {
+                Some(last) if last == (x, y) => {}
+                Some((last_x, last_y)) if deltas => changes.push((other, x - last_x, y - last_y)),
+                _ => positions.push((other, x, y)),
+            }
+            if peer.seen_zones.insert(other, zone) != Some(zone) {
+                zones.push((other, zone));
+            }
+        }
+
+        if positions.is_empty() && changes.is_empty() && zones.is_empty() {
+            return Ok(());
+        }
+        let msg = ServerMessage::Positions {
+            positions,
+            deltas: changes,
+            zones: zones
+                .into_iter()
+                .map(|(other, zone)| (other, self.zone_name(zone)))
+                .collect(),
+        };
+        let peer = &self.peers[&id];
+        if let Some(outbox) = &peer.outbox {
+            if !outbox.send_positions(encode(&msg)?) {
+                self.evict(id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fans a chat message out to the peers it is addressed to, and back to its sender.
+    fn chat(&mut self, id: PeerId, scope: ChatScope, text: String) -> Result<(), Error> {
+        let config = &self.rooms.0.config;
+        let error = |code, message: String| ProtocolError {
+            code,
+            message,
+            in_reply_to: Some("Chat".to_owned()),
+        };
+
+        if text.chars().count() > config.max_chat_length {
+            let message = format!(
+                "Chat messages are limited to {} characters",
+                config.max_chat_length
+            );
+            return self.reject(id, error(ErrorCode::InvalidMessage, message));
+        }
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        if !peer.chat_limit.take() {
+            let message = "Sending chat messages too quickly".to_owned();
+            return self.reject(id, error(ErrorCode::RateLimited, message));
+        }
+        let pos = peer.pos;
+
+        let (scope, mut recipients) = match scope {
+            ChatScope::Peer { peer } if !self.peers.contains_key(&peer) => {
+                let message = format!("No peer {} in room", peer);
+                return self.reject(id, error(ErrorCode::UnknownPeer, message));
+            }
+            ChatScope::Peer { peer } => (scope, vec![peer]),
+            ChatScope::Nearby { radius } => {
+                let radius = radius.max(0.0).min(config.max_interest_radius);
+                let nearby = if self.spectators.contains(&id) {
+                    Vec::new()
+                } else {
+                    self.grid.query(pos, radius)
+                };
+                (ChatScope::Nearby { radius }, nearby)
+            }
+            ChatScope::Bubble => match self.bubbles.of(id) {
+                Some(bubble) => (scope, self.bubbles.members(bubble).collect()),
+                None => (scope, Vec::new()),
+            },
+            ChatScope::Room => (scope, self.peers.keys().copied().collect()),
+        };
+        if !recipients.contains(&id) {
+            recipients.push(id);
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_millis() as u64)
+            .unwrap_or(0);
+        let history_id = match scope {
+            ChatScope::Room => Some(self.history.push(id, text.clone(), time)),
+            _ => None,
+        };
+        let msg = ServerMessage::Chat {
+            from: id,
+            scope,
+            text,
+            time,
+            id: history_id,
+        };
+        self.send_all(&recipients, &msg)
+    }
+
+    /// Replies to a message that could not be handled, and removes the peer if it has sent too
+    /// many of them.
+    fn reject(&mut self, id: PeerId, error: ProtocolError) -> Result<(), Error> {
+        let code = error.code;
+        self.send(id, &error.into())?;
+
+        // Races with other peers and hitting limits happen to well-behaved clients too
+        if !code.is_malformed() {
+            return Ok(());
+        }
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        if peer.protocol_errors.take() {
+            return Ok(());
+        }
+
+        eprintln!(
+            "Removing peer {} from room {}: too many protocol errors",
+            id, self.name
+        );
+        self.kick(id, "Too many protocol errors")
+    }
+
+    /// Sends a message to a peer, dropping it if the peer is waiting to resume its session or
+    /// does not understand it.
+    fn send(&mut self, id: PeerId, msg: &ServerMessage) -> Result<(), Error> {
+        self.send_all(&[id], msg)
+    }
+
+    /// Sends a message to each of `ids`.
+    fn send_all(&mut self, ids: &[PeerId], msg: &ServerMessage) -> Result<(), Error> {
+        let recipients = ids.iter().filter_map(|id| self.peers.get_key_value(id));
+        let closed = deliver(recipients, msg)?;
+        for id in closed {
+            self.evict(id);
+        }
+        Ok(())
+    }
+
+    fn broadcast(&mut self, msg: &ServerMessage, except: Option<PeerId>) -> Result<(), Error> {
+        let recipients = self.peers.iter().filter(|(&id, _)| Some(id) != except);
+        let closed = deliver(recipients, msg)?;
+        for id in closed {
+            self.evict(id);
+        }
+        Ok(())
+    }
+
+    /// Disconnects a peer whose outbox has been closed, for falling behind or losing its writer.
+    /// It may still resume its session.
+    fn evict(&mut self, id: PeerId) {
+        if self
+            .peers
+            .get(&id)
+            .map_or(false, |peer| peer.outbox.is_some())
+        {
+            self.disconnect(id);
+        }
+    }
+}
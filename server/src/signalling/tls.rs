@@ -0,0 +1,198 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use super::config::TlsFiles;
+use super::Error;
+
+/// Accepts TLS connections with a certificate loaded from PEM files, reloaded when they change.
+pub struct Tls {
+    files: TlsFiles,
+    current: Mutex<Loaded>,
+}
+
+struct Loaded {
+    /// When the certificate and key files had last been modified as of the last attempt to load
+    /// them
+    modified: (SystemTime, SystemTime),
+    acceptor: TlsAcceptor,
+}
+
+fn modified(files: &TlsFiles) -> io::Result<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&files.cert)?.modified()?;
+    let key = fs::metadata(&files.key)?.modified()?;
+    Ok((cert, key))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path).map(BufReader::new)
+}
+
+/// Reads the first private key from a PEM file, in either PKCS #8 or RSA format.
+fn private_key(path: &Path) -> Result<PrivateKey, Error> {
+    let invalid = || Error::InvalidCertificate(path.to_owned());
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(|()| invalid())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(|()| invalid())?;
+    }
+    keys.into_iter().next().ok_or_else(invalid)
+}
+
+fn load(files: &TlsFiles) -> Result<Loaded, Error> {
+    let modified = modified(files)?;
+    let certs = pemfile::certs(&mut open(&files.cert)?)
+        .ok()
+        .filter(|certs| !certs.is_empty())
+        .ok_or_else(|| Error::InvalidCertificate(files.cert.clone()))?;
+    let key = private_key(&files.key)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)?;
+    // WebSockets are only upgraded from HTTP/1.1
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    Ok(Loaded {
+        modified,
+        acceptor: Arc::new(config).into(),
+    })
+}
+
+impl Tls {
+    pub fn load(files: &TlsFiles) -> Result<Self, Error> {
+        Ok(Tls {
+            files: files.clone(),
+            current: Mutex::new(load(files)?),
+        })
+    }
+
+    /// The acceptor for a new connection, with the certificate reloaded first if either file
+    /// changed. The previous certificate is kept if the new one cannot be loaded, and it is not
+    /// tried again until the files change once more, as they may be half written.
+    pub fn acceptor(&self) -> Result<TlsAcceptor, Error> {
+        let mut current = self.current.lock()?;
+        match modified(&self.files) {
+            Ok(modified) if modified == current.modified => {}
+            Ok(modified) => match load(&self.files) {
+                Ok(loaded) => {
+                    eprintln!("Reloaded TLS certificate {:?}", self.files.cert);
+                    *current = loaded;
+                }
+                Err(e) => {
+                    eprintln!("Could not reload TLS certificate: {:?}", e);
+                    current.modified = modified;
+                }
+            },
+            Err(e) => eprintln!("Could not check TLS certificate for changes: {}", e),
+        }
+        Ok(current.acceptor.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use tokio::runtime;
+    use tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    /// A directory of its own for each test, as they run in parallel.
+    fn dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("webrtc-tls-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn certificate() -> rcgen::Certificate {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap()
+    }
+
+    fn write(files: &TlsFiles, cert: &rcgen::Certificate) {
+        // Without a pause the files may not look modified, if the clock is coarse
+        thread::sleep(Duration::from_millis(20));
+        fs::write(&files.cert, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&files.key, cert.serialize_private_key_pem()).unwrap();
+    }
+
+    fn files(test: &str, cert: &rcgen::Certificate) -> TlsFiles {
+        let dir = dir(test);
+        let files = TlsFiles {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+        };
+        write(&files, cert);
+        files
+    }
+
+    /// Whether a client that trusts only `cert` can connect.
+    fn serves(tls: &Tls, cert: &rcgen::Certificate) -> bool {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let mut config = ClientConfig::new();
+        config.root_store = roots;
+        let connector = TlsConnector::from(Arc::new(config));
+        let acceptor = tls.acceptor().unwrap();
+
+        let rt = runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(16 * 1024);
+            let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let (client, _) =
+                futures::join!(connector.connect(domain, client), acceptor.accept(server));
+            client.is_ok()
+        })
+    }
+
+    #[test]
+    fn load() {
+        let cert = certificate();
+        let tls = Tls::load(&files("load", &cert)).unwrap();
+        assert!(serves(&tls, &cert));
+        assert!(!serves(&tls, &certificate()));
+    }
+
+    #[test]
+    fn reload() {
+        let (old, new) = (certificate(), certificate());
+        let files = files("reload", &old);
+        let tls = Tls::load(&files).unwrap();
+        write(&files, &new);
+        assert!(serves(&tls, &new));
+        assert!(!serves(&tls, &old));
+    }
+
+    #[test]
+    fn keep_on_invalid() {
+        let cert = certificate();
+        let files = files("keep_on_invalid", &cert);
+        let tls = Tls::load(&files).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        fs::write(&files.cert, "-----BEGIN CERTIFICATE-----\nnot base64\n").unwrap();
+        assert!(serves(&tls, &cert));
+        // Not tried again until the files change
+        assert!(serves(&tls, &cert));
+    }
+
+    #[test]
+    fn invalid() {
+        let files = files("invalid", &certificate());
+        fs::write(&files.key, "").unwrap();
+        assert!(matches!(
+            Tls::load(&files),
+            Err(Error::InvalidCertificate(path)) if path == files.key
+        ));
+    }
+}
@@ -19,7 +19,8 @@ use serde_json::json;
 use tokio::runtime;
 use tokio_tungstenite::tungstenite;
 
-use crate::signalling::message::{PeerMessage, PeerMessageData, ServerMessage};
+use crate::signalling::message::{PeerId, PeerMessage, PeerMessageData, ServerMessage};
+use crate::signalling::protocol;
 
 pub use error::Error;
 
@@ -31,7 +32,7 @@ where
         + Send
         + 'static,
 {
-    let mut peers: HashMap<usize, _> = HashMap::new();
+    let mut peers: HashMap<PeerId, _> = HashMap::new();
     let (tx, rx) = mpsc::unbounded::<PeerMessage>();
 
     let pipeline = gst::Pipeline::new(Some("pipeline"));
@@ -159,18 +160,12 @@ where
                 tungstenite::Message::Text(content) => {
                     let msg = serde_json::from_str::<ServerMessage>(&content).unwrap();
                     match msg {
-                        ServerMessage::Hello {
-                            peers: remote_peers,
-                            ..
-                        } => {
-                            remote_peers.iter().for_each(|peer| {
-                                peers.insert(peer.id, add_peer(peer.id, true));
-                            });
+                        // Peers are connected to once they come into range
+                        ServerMessage::Hello { .. } | ServerMessage::AddPeer { .. } => {}
+                        ServerMessage::InRange { peer, polite, .. } => {
+                            peers.insert(peer, add_peer(peer, polite));
                         }
-                        ServerMessage::AddPeer { peer } => {
-                            peers.insert(peer.id, add_peer(peer.id, false));
-                        }
-                        ServerMessage::RemovePeer { peer } => {
+                        ServerMessage::OutOfRange { peer } | ServerMessage::RemovePeer { peer } => {
                             peers.remove(&peer);
                         }
                         ServerMessage::PeerMessage {
@@ -269,7 +264,18 @@ where
                                 }
                             }
                         },
-                        ServerMessage::MovePeer { .. } => {}
+                        ServerMessage::MovePeer { .. }
+                        | ServerMessage::PeerUpdated { .. }
+                        | ServerMessage::Presence { .. }
+                        | ServerMessage::MuteRequested { .. }
+                        | ServerMessage::Positions { .. }
+                        | ServerMessage::BubbleChanged { .. }
+                        | ServerMessage::Stage { .. }
+                        | ServerMessage::Chat { .. }
+                        | ServerMessage::History { .. } => {}
+                        ServerMessage::Error { code, message, .. } => {
+                            eprintln!("Signalling error {:?}: {}", code, message);
+                        }
                     };
                 }
                 _ => {}
@@ -304,7 +310,13 @@ pub fn main() -> Result<(), Error> {
         tokio::net::TcpStream::from_std(connection)?
     };
 
-    let connection = tokio_tungstenite::client_async("ws://localhost:4000", connection)
+    let request = tungstenite::handshake::client::Request::builder()
+        .uri("ws://localhost:4000")
+        .header("Sec-WebSocket-Protocol", protocol::CURRENT)
+        .body(())
+        .unwrap();
+
+    let connection = tokio_tungstenite::client_async(request, connection)
         .map_ok(|(s, _)| s)
         .err_into()
         .and_then(handle_messages);